@@ -0,0 +1,52 @@
+//! Compares parsing an overlay with freshly loaded fonts, as every layer used to do, against
+//! parsing it with the options the server shares through the application state and the
+//! options it builds for a request with strict fonts.
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use imtrand::{config::Config, fonts::Fonts, svg::parse_svg};
+
+const OVERLAY: &[u8] = include_bytes!("../resources/Overlay.svg");
+
+fn parse_overlay(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse overlay");
+    group.sample_size(20);
+
+    let config = Config::default();
+    group.bench_function("fonts loaded per layer", |b| {
+        b.iter(|| {
+            let fonts = Fonts::load(config.font_settings(), config.limits());
+            let options = fonts.shared_options();
+            parse_svg(black_box(OVERLAY), &options)
+                .ok()
+                .expect("the overlay parses")
+        })
+    });
+
+    let fonts = Fonts::load(config.font_settings(), config.limits());
+    group.bench_function("shared options", |b| {
+        b.iter(|| {
+            let options = fonts
+                .svg_options(&[], None)
+                .ok()
+                .expect("no fonts are uploaded");
+            parse_svg(black_box(OVERLAY), &options)
+                .ok()
+                .expect("the overlay parses")
+        })
+    });
+
+    group.bench_function("strict options", |b| {
+        b.iter(|| {
+            let options = fonts
+                .svg_options(&[], Some(true))
+                .ok()
+                .expect("no fonts are uploaded");
+            // fails when the fonts of the overlay are not installed, after the same work
+            parse_svg(black_box(OVERLAY), &options).ok()
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, parse_overlay);
+criterion_main!(benches);
@@ -0,0 +1,170 @@
+use imageproc::image::{imageops::overlay, DynamicImage, GenericImage, GenericImageView, Rgba};
+use serde::Deserialize;
+
+/// Separable blend modes as defined in the W3C compositing spec.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Darken,
+    Lighten,
+    Difference,
+    SoftLight,
+    HardLight,
+}
+
+impl BlendMode {
+    /// Blends a backdrop channel with a source channel, both in the range 0-1.
+    fn apply(self, backdrop: f32, source: f32) -> f32 {
+        match self {
+            BlendMode::Normal => source,
+            BlendMode::Multiply => backdrop * source,
+            BlendMode::Screen => backdrop + source - backdrop * source,
+            BlendMode::Overlay => BlendMode::HardLight.apply(source, backdrop),
+            BlendMode::Darken => backdrop.min(source),
+            BlendMode::Lighten => backdrop.max(source),
+            BlendMode::Difference => (backdrop - source).abs(),
+            BlendMode::SoftLight => {
+                if source <= 0.5 {
+                    backdrop - (1.0 - 2.0 * source) * backdrop * (1.0 - backdrop)
+                } else {
+                    let d = if backdrop <= 0.25 {
+                        ((16.0 * backdrop - 12.0) * backdrop + 4.0) * backdrop
+                    } else {
+                        backdrop.sqrt()
+                    };
+                    backdrop + (2.0 * source - 1.0) * (d - backdrop)
+                }
+            }
+            BlendMode::HardLight => {
+                if source <= 0.5 {
+                    BlendMode::Multiply.apply(backdrop, 2.0 * source)
+                } else {
+                    BlendMode::Screen.apply(backdrop, 2.0 * source - 1.0)
+                }
+            }
+        }
+    }
+
+    fn blend_pixel(self, backdrop: Rgba<u8>, source: Rgba<u8>, opacity: f32) -> Rgba<u8> {
+        let alpha_b = backdrop[3] as f32 / 255.0;
+        let alpha_s = source[3] as f32 / 255.0 * opacity;
+        let alpha_o = alpha_s + alpha_b * (1.0 - alpha_s);
+        if alpha_o <= 0.0 {
+            return Rgba([0, 0, 0, 0]);
+        }
+
+        let mut result = [0; 4];
+        for channel in 0..3 {
+            let cb = backdrop[channel] as f32 / 255.0;
+            let cs = source[channel] as f32 / 255.0;
+            let mixed = (1.0 - alpha_b) * cs + alpha_b * self.apply(cb, cs);
+            let co = (alpha_s * mixed + alpha_b * cb * (1.0 - alpha_s)) / alpha_o;
+            result[channel] = (co * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+        result[3] = (alpha_o * 255.0).round() as u8;
+        Rgba(result)
+    }
+}
+
+/// Draws `top` onto `bottom` at the given position using the blend mode and opacity (0-1).
+pub fn composite(
+    bottom: &mut DynamicImage,
+    top: &DynamicImage,
+    x: i64,
+    y: i64,
+    mode: BlendMode,
+    opacity: f32,
+) {
+    let opacity = opacity.clamp(0.0, 1.0);
+    if mode == BlendMode::Normal && opacity >= 1.0 {
+        overlay(bottom, top, x, y);
+        return;
+    }
+
+    let x_start = x.max(0);
+    let y_start = y.max(0);
+    let x_end = (x + top.width() as i64).min(bottom.width() as i64);
+    let y_end = (y + top.height() as i64).min(bottom.height() as i64);
+    for bottom_y in y_start..y_end {
+        for bottom_x in x_start..x_end {
+            let source = top.get_pixel((bottom_x - x) as u32, (bottom_y - y) as u32);
+            let (bottom_x, bottom_y) = (bottom_x as u32, bottom_y as u32);
+            let backdrop = bottom.get_pixel(bottom_x, bottom_y);
+            bottom.put_pixel(
+                bottom_x,
+                bottom_y,
+                mode.blend_pixel(backdrop, source, opacity),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRAY: Rgba<u8> = Rgba([128, 128, 128, 255]);
+
+    #[test]
+    fn multiply_darkens() {
+        assert_eq!(BlendMode::Multiply.apply(0.5, 0.5), 0.25);
+        assert_eq!(
+            BlendMode::Multiply.blend_pixel(GRAY, GRAY, 1.0),
+            Rgba([64, 64, 64, 255])
+        );
+    }
+
+    #[test]
+    fn screen_lightens() {
+        assert_eq!(BlendMode::Screen.apply(0.5, 0.5), 0.75);
+        assert_eq!(
+            BlendMode::Screen.blend_pixel(GRAY, GRAY, 1.0),
+            Rgba([192, 192, 192, 255])
+        );
+    }
+
+    #[test]
+    fn half_opacity_mixes() {
+        let white = Rgba([255, 255, 255, 255]);
+        let black = Rgba([0, 0, 0, 255]);
+        assert_eq!(
+            BlendMode::Normal.blend_pixel(white, black, 0.5),
+            Rgba([128, 128, 128, 255])
+        );
+    }
+
+    #[test]
+    fn zero_opacity_keeps_backdrop() {
+        let backdrop = Rgba([10, 120, 240, 200]);
+        for mode in [
+            BlendMode::Normal,
+            BlendMode::Multiply,
+            BlendMode::Difference,
+            BlendMode::SoftLight,
+        ] {
+            assert_eq!(mode.blend_pixel(backdrop, GRAY, 0.0), backdrop);
+        }
+    }
+
+    #[test]
+    fn transparent_backdrop_shows_source() {
+        let source = Rgba([200, 100, 50, 255]);
+        for mode in [BlendMode::Multiply, BlendMode::Screen, BlendMode::Darken] {
+            assert_eq!(mode.blend_pixel(Rgba([0, 0, 0, 0]), source, 1.0), source);
+        }
+    }
+
+    #[test]
+    fn transparent_pixels_stay_transparent() {
+        let transparent = Rgba([0, 0, 0, 0]);
+        assert_eq!(
+            BlendMode::Multiply.blend_pixel(transparent, transparent, 1.0),
+            transparent
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use imageproc::image::DynamicImage;
+use resvg::tiny_skia::{IntSize, Transform};
+use serde::Deserialize;
+
+use crate::{
+    fonts::SvgOptions,
+    limits::Limits,
+    svg::{escape, parse_svg, render_svg},
+    AppError,
+};
+
+/// Default direction of linear gradients, top to bottom as in css.
+const DEFAULT_GRADIENT_ANGLE: f32 = 180.0;
+
+/// An empty image to draw the layers on, used instead of a base image.
+#[derive(Deserialize)]
+pub struct Canvas {
+    pub width: u32,
+    pub height: u32,
+    /// Any svg color, the canvas is transparent without a background or gradient.
+    pub background: Option<String>,
+    /// Painted over the background color.
+    pub gradient: Option<Gradient>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Gradient {
+    /// `angle` is the direction in degrees like in css, 0 goes up and 90 to the right.
+    Linear {
+        angle: Option<f32>,
+        stops: Vec<ColorStop>,
+    },
+    /// Circle from the center to the corners.
+    Radial { stops: Vec<ColorStop> },
+}
+
+#[derive(Deserialize)]
+pub struct ColorStop {
+    pub color: String,
+    /// Position from 0 to 1, stops without one are spread evenly.
+    pub offset: Option<f32>,
+}
+
+impl Canvas {
+    pub fn render(
+        &self,
+        svg_options: &SvgOptions,
+        limits: Limits,
+    ) -> Result<DynamicImage, AppError> {
+        limits.check_output(self.width, self.height)?;
+        let size = IntSize::from_wh(self.width, self.height).ok_or(AppError::InvalidSize)?;
+        let (width, height) = (self.width as f32, self.height as f32);
+        let mut content = String::new();
+        if let Some(color) = &self.background {
+            content.push_str(&format!(
+                r#"<rect width="100%" height="100%" fill="{}"/>"#,
+                escape(color)
+            ));
+        }
+        if let Some(gradient) = &self.gradient {
+            let (element, stops) = match gradient {
+                Gradient::Linear { angle, stops } => {
+                    // the gradient line of css, long enough that the corners get the end colors
+                    let (sin, cos) = angle
+                        .unwrap_or(DEFAULT_GRADIENT_ANGLE)
+                        .to_radians()
+                        .sin_cos();
+                    let half = (width * sin.abs() + height * cos.abs()) / 2.0;
+                    let (cx, cy) = (width / 2.0, height / 2.0);
+                    (
+                        format!(
+                            r#"<linearGradient id="gradient" gradientUnits="userSpaceOnUse" x1="{}" y1="{}" x2="{}" y2="{}">"#,
+                            cx - sin * half,
+                            cy + cos * half,
+                            cx + sin * half,
+                            cy - cos * half
+                        ),
+                        stops,
+                    )
+                }
+                Gradient::Radial { stops } => (
+                    format!(
+                        r#"<radialGradient id="gradient" gradientUnits="userSpaceOnUse" cx="{}" cy="{}" r="{}">"#,
+                        width / 2.0,
+                        height / 2.0,
+                        width.hypot(height) / 2.0
+                    ),
+                    stops,
+                ),
+            };
+            if stops.is_empty() {
+                return Err(AppError::InvalidCanvas(
+                    "a gradient needs at least one color stop".into(),
+                ));
+            }
+            content.push_str(&element);
+            for (index, stop) in stops.iter().enumerate() {
+                let offset = stop
+                    .offset
+                    .unwrap_or(index as f32 / (stops.len() - 1).max(1) as f32);
+                content.push_str(&format!(
+                    r#"<stop offset="{}" stop-color="{}"/>"#,
+                    offset.clamp(0.0, 1.0),
+                    escape(&stop.color)
+                ));
+            }
+            let end = match gradient {
+                Gradient::Linear { .. } => "</linearGradient>",
+                Gradient::Radial { .. } => "</radialGradient>",
+            };
+            content.push_str(end);
+            content.push_str(r#"<rect width="100%" height="100%" fill="url(#gradient)"/>"#);
+        }
+
+        let document = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}">{}</svg>"#,
+            self.width, self.height, content
+        );
+        let tree = parse_svg(document.as_bytes(), svg_options)?;
+        render_svg(&tree, size, Transform::identity())
+    }
+}
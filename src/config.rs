@@ -0,0 +1,254 @@
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    num::NonZeroUsize,
+    path::PathBuf,
+    thread,
+};
+
+use clap::{builder::BoolishValueParser, Parser};
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+
+use crate::{fonts::FontSettings, limits::Limits, output::OutputFormat};
+
+/// Read when no config file is given and it exists.
+const DEFAULT_CONFIG_FILE: &str = "imtrand.toml";
+
+/// Image compositing server.
+///
+/// Every flag can also be set with its environment variable, both override the config file.
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    /// TOML file with the settings, defaults to imtrand.toml if it exists.
+    #[arg(long, env = "IMTRAND_CONFIG")]
+    config: Option<PathBuf>,
+    #[arg(long, env = "IMTRAND_BIND_ADDRESS")]
+    bind_address: Option<IpAddr>,
+    #[arg(long, env = "IMTRAND_PORT")]
+    port: Option<u16>,
+    /// Largest request body in bytes.
+    #[arg(long, env = "IMTRAND_MAX_UPLOAD_SIZE")]
+    max_upload_size: Option<usize>,
+    /// Largest width of decoded and produced images in pixels.
+    #[arg(long, env = "IMTRAND_MAX_WIDTH")]
+    max_width: Option<u32>,
+    /// Largest height of decoded and produced images in pixels.
+    #[arg(long, env = "IMTRAND_MAX_HEIGHT")]
+    max_height: Option<u32>,
+    /// Largest width times height of decoded and produced images.
+    #[arg(long, env = "IMTRAND_MAX_PIXELS")]
+    max_pixels: Option<u64>,
+    /// Most layers, stickers, texts and overlays in one request.
+    #[arg(long, env = "IMTRAND_MAX_LAYERS")]
+    max_layers: Option<usize>,
+    /// Most steps of a pipeline and entries of a transforms or filters list.
+    #[arg(long, env = "IMTRAND_MAX_STEPS")]
+    max_steps: Option<usize>,
+    /// Images processed at the same time, defaults to the number of cores.
+    #[arg(long, env = "IMTRAND_MAX_JOBS")]
+    max_jobs: Option<usize>,
+    /// Requests waiting for a free job before new ones are turned away.
+    #[arg(long, env = "IMTRAND_MAX_QUEUED_JOBS")]
+    max_queued_jobs: Option<usize>,
+    /// Threads of the async runtime, defaults to the number of cores.
+    #[arg(long, env = "IMTRAND_WORKER_THREADS")]
+    worker_threads: Option<usize>,
+    /// Format used when the request has no format and accepts anything.
+    #[arg(long, env = "IMTRAND_DEFAULT_FORMAT", value_enum)]
+    default_format: Option<OutputFormat>,
+    /// Directory the templates are loaded from and stored in.
+    #[arg(long, env = "IMTRAND_TEMPLATE_DIR")]
+    template_dir: Option<PathBuf>,
+    /// Directory with additional font files.
+    #[arg(long, env = "IMTRAND_FONT_DIR")]
+    font_dir: Option<PathBuf>,
+    /// Whether the fonts installed on the host are loaded as well.
+    #[arg(long, env = "IMTRAND_SYSTEM_FONTS", value_parser = BoolishValueParser::new())]
+    system_fonts: Option<bool>,
+    /// Family used for generic families and text without a font-family.
+    #[arg(long, env = "IMTRAND_DEFAULT_FONT_FAMILY")]
+    default_font_family: Option<String>,
+    /// Default for the strict_fonts option of requests.
+    #[arg(long, env = "IMTRAND_STRICT_FONTS", value_parser = BoolishValueParser::new())]
+    strict_fonts: Option<bool>,
+}
+
+/// Settings of the server, read once at startup.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind_address: IpAddr,
+    pub port: u16,
+    pub max_upload_size: usize,
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_pixels: u64,
+    pub max_layers: usize,
+    pub max_steps: usize,
+    pub max_jobs: Option<usize>,
+    pub max_queued_jobs: usize,
+    pub worker_threads: Option<usize>,
+    pub default_format: OutputFormat,
+    pub template_dir: PathBuf,
+    pub font_dir: Option<PathBuf>,
+    pub system_fonts: bool,
+    pub default_font_family: Option<String>,
+    pub strict_fonts: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 3000,
+            // the default of axum
+            max_upload_size: 2 * 1024 * 1024,
+            max_width: 10_000,
+            max_height: 10_000,
+            max_pixels: 32_000_000,
+            max_layers: 64,
+            max_steps: 32,
+            max_jobs: None,
+            max_queued_jobs: 64,
+            worker_threads: None,
+            default_format: OutputFormat::Jpeg,
+            template_dir: "templates".into(),
+            font_dir: None,
+            system_fonts: true,
+            default_font_family: None,
+            strict_fonts: false,
+        }
+    }
+}
+
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(&'static str),
+    /// A setting names a path that is not a directory.
+    NotADirectory(&'static str, PathBuf),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "cannot read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "invalid {}: {}", path.display(), err),
+            ConfigError::Invalid(reason) => f.write_str(reason),
+            ConfigError::NotADirectory(setting, path) => {
+                write!(f, "{} {} is not a directory", setting, path.display())
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file and applies the environment and the command line on top of it.
+    pub fn load() -> Result<Config, ConfigError> {
+        let args = Args::parse();
+        let mut config = match &args.config {
+            Some(path) => Config::read(path.clone())?,
+            None if fs::metadata(DEFAULT_CONFIG_FILE).is_ok() => {
+                Config::read(DEFAULT_CONFIG_FILE.into())?
+            }
+            None => Config::default(),
+        };
+
+        config.bind_address = args.bind_address.unwrap_or(config.bind_address);
+        config.port = args.port.unwrap_or(config.port);
+        config.max_upload_size = args.max_upload_size.unwrap_or(config.max_upload_size);
+        config.max_width = args.max_width.unwrap_or(config.max_width);
+        config.max_height = args.max_height.unwrap_or(config.max_height);
+        config.max_pixels = args.max_pixels.unwrap_or(config.max_pixels);
+        config.max_layers = args.max_layers.unwrap_or(config.max_layers);
+        config.max_steps = args.max_steps.unwrap_or(config.max_steps);
+        config.max_jobs = args.max_jobs.or(config.max_jobs);
+        config.max_queued_jobs = args.max_queued_jobs.unwrap_or(config.max_queued_jobs);
+        config.worker_threads = args.worker_threads.or(config.worker_threads);
+        config.default_format = args.default_format.unwrap_or(config.default_format);
+        config.template_dir = args.template_dir.unwrap_or(config.template_dir);
+        config.font_dir = args.font_dir.or(config.font_dir);
+        config.system_fonts = args.system_fonts.unwrap_or(config.system_fonts);
+        config.default_font_family = args.default_font_family.or(config.default_font_family);
+        config.strict_fonts = args.strict_fonts.unwrap_or(config.strict_fonts);
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn read(path: PathBuf) -> Result<Config, ConfigError> {
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) => return Err(ConfigError::Read(path, err)),
+        };
+        toml::from_str(&contents).map_err(|err| ConfigError::Parse(path, err))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.port == 0 {
+            return Err(ConfigError::Invalid("port has to be between 1 and 65535"));
+        }
+        if self.max_upload_size == 0 {
+            return Err(ConfigError::Invalid("max_upload_size has to be above 0"));
+        }
+        if self.max_width == 0 || self.max_height == 0 || self.max_pixels == 0 {
+            return Err(ConfigError::Invalid(
+                "max_width, max_height and max_pixels have to be above 0",
+            ));
+        }
+        if self.max_jobs == Some(0) {
+            return Err(ConfigError::Invalid("max_jobs has to be above 0"));
+        }
+        // both are permits of the semaphore that admits the jobs
+        let permits = self.max_jobs().checked_add(self.max_queued_jobs);
+        if permits.is_none_or(|permits| permits > Semaphore::MAX_PERMITS) {
+            return Err(ConfigError::Invalid(
+                "max_jobs plus max_queued_jobs is more than the server can queue",
+            ));
+        }
+        if self.worker_threads == Some(0) {
+            return Err(ConfigError::Invalid("worker_threads has to be above 0"));
+        }
+        // a missing template directory is created by the first upload
+        if self.template_dir.exists() && !self.template_dir.is_dir() {
+            return Err(ConfigError::NotADirectory(
+                "template_dir",
+                self.template_dir.clone(),
+            ));
+        }
+        if let Some(font_dir) = &self.font_dir {
+            if !font_dir.is_dir() {
+                return Err(ConfigError::NotADirectory("font_dir", font_dir.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_width: self.max_width,
+            max_height: self.max_height,
+            max_pixels: self.max_pixels,
+        }
+    }
+
+    pub fn font_settings(&self) -> FontSettings {
+        FontSettings {
+            directory: self.font_dir.clone(),
+            system_fonts: self.system_fonts,
+            default_family: self.default_font_family.clone(),
+            strict: self.strict_fonts,
+        }
+    }
+
+    pub fn max_jobs(&self) -> usize {
+        self.max_jobs
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, NonZeroUsize::get))
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.port)
+    }
+}
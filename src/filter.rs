@@ -0,0 +1,272 @@
+use imageproc::{
+    filter::gaussian_blur_f32,
+    image::{DynamicImage, ImageBuffer, Rgba, RgbaImage},
+    map::{map_colors2, map_colors_mut},
+};
+use serde::Deserialize;
+
+use crate::AppError;
+
+/// An adjustment of the colors or sharpness, the color filters follow the css filter functions.
+#[derive(Deserialize)]
+#[serde(tag = "filter", rename_all = "kebab-case")]
+pub enum Filter {
+    /// Multiplies the colors, 1 keeps them and 0 is black.
+    Brightness {
+        amount: f32,
+    },
+    /// 1 keeps the image and 0 is a flat gray.
+    Contrast {
+        amount: f32,
+    },
+    /// 1 keeps the image, 0 is grayscale and more than 1 oversaturates.
+    Saturation {
+        amount: f32,
+    },
+    /// Rotates the hues by `degrees`.
+    HueRotate {
+        degrees: f32,
+    },
+    /// Amounts go from 0 (unchanged) to 1 (default, full effect).
+    Grayscale {
+        amount: Option<f32>,
+    },
+    Sepia {
+        amount: Option<f32>,
+    },
+    Invert {
+        amount: Option<f32>,
+    },
+    Blur {
+        sigma: f32,
+    },
+    /// Unsharp mask, `amount` scales the difference to the blurred image and differences below
+    /// `threshold` (0-255) are left alone.
+    Sharpen {
+        sigma: Option<f32>,
+        amount: Option<f32>,
+        threshold: Option<u8>,
+    },
+}
+
+const DEFAULT_SHARPEN_SIGMA: f32 = 1.0;
+/// The blur kernel has 4 * sigma + 1 taps per pixel, larger ones would take too long.
+const MAX_SIGMA: f32 = 50.0;
+
+/// Applies the filters in order.
+pub fn apply_filters(image: DynamicImage, filters: &[Filter]) -> Result<DynamicImage, AppError> {
+    if filters.is_empty() {
+        return Ok(image);
+    }
+    let image = filters
+        .iter()
+        .try_fold(image.into_rgba8(), |image, filter| filter.apply(image))?;
+    Ok(DynamicImage::ImageRgba8(image))
+}
+
+impl Filter {
+    fn apply(&self, mut image: RgbaImage) -> Result<RgbaImage, AppError> {
+        match *self {
+            Filter::Brightness { amount } => {
+                map_rgb(&mut image, |[r, g, b]| [r * amount, g * amount, b * amount])
+            }
+            Filter::Contrast { amount } => map_rgb(&mut image, |color| {
+                color.map(|channel| (channel - 0.5) * amount + 0.5)
+            }),
+            Filter::Saturation { amount } => {
+                let s = amount.max(0.0);
+                multiply(
+                    &mut image,
+                    [
+                        [0.213 + 0.787 * s, 0.715 - 0.715 * s, 0.072 - 0.072 * s],
+                        [0.213 - 0.213 * s, 0.715 + 0.285 * s, 0.072 - 0.072 * s],
+                        [0.213 - 0.213 * s, 0.715 - 0.715 * s, 0.072 + 0.928 * s],
+                    ],
+                )
+            }
+            Filter::HueRotate { degrees } => {
+                let (sin, cos) = degrees.to_radians().sin_cos();
+                multiply(
+                    &mut image,
+                    [
+                        [
+                            0.213 + cos * 0.787 - sin * 0.213,
+                            0.715 - cos * 0.715 - sin * 0.715,
+                            0.072 - cos * 0.072 + sin * 0.928,
+                        ],
+                        [
+                            0.213 - cos * 0.213 + sin * 0.143,
+                            0.715 + cos * 0.285 + sin * 0.140,
+                            0.072 - cos * 0.072 - sin * 0.283,
+                        ],
+                        [
+                            0.213 - cos * 0.213 - sin * 0.787,
+                            0.715 - cos * 0.715 + sin * 0.715,
+                            0.072 + cos * 0.928 + sin * 0.072,
+                        ],
+                    ],
+                )
+            }
+            Filter::Grayscale { amount } => {
+                let a = 1.0 - amount.unwrap_or(1.0).clamp(0.0, 1.0);
+                multiply(
+                    &mut image,
+                    [
+                        [
+                            0.2126 + 0.7874 * a,
+                            0.7152 - 0.7152 * a,
+                            0.0722 - 0.0722 * a,
+                        ],
+                        [
+                            0.2126 - 0.2126 * a,
+                            0.7152 + 0.2848 * a,
+                            0.0722 - 0.0722 * a,
+                        ],
+                        [
+                            0.2126 - 0.2126 * a,
+                            0.7152 - 0.7152 * a,
+                            0.0722 + 0.9278 * a,
+                        ],
+                    ],
+                )
+            }
+            Filter::Sepia { amount } => {
+                let a = 1.0 - amount.unwrap_or(1.0).clamp(0.0, 1.0);
+                multiply(
+                    &mut image,
+                    [
+                        [0.393 + 0.607 * a, 0.769 - 0.769 * a, 0.189 - 0.189 * a],
+                        [0.349 - 0.349 * a, 0.686 + 0.314 * a, 0.168 - 0.168 * a],
+                        [0.272 - 0.272 * a, 0.534 - 0.534 * a, 0.131 + 0.869 * a],
+                    ],
+                )
+            }
+            Filter::Invert { amount } => {
+                let a = amount.unwrap_or(1.0).clamp(0.0, 1.0);
+                map_rgb(&mut image, |color| {
+                    color.map(|channel| a * (1.0 - channel) + (1.0 - a) * channel)
+                })
+            }
+            Filter::Blur { sigma } => {
+                return Ok(blur(&image, positive_sigma(sigma)?));
+            }
+            Filter::Sharpen {
+                sigma,
+                amount,
+                threshold,
+            } => {
+                let sigma = positive_sigma(sigma.unwrap_or(DEFAULT_SHARPEN_SIGMA))?;
+                let amount = amount.unwrap_or(1.0);
+                let threshold = threshold.unwrap_or(0) as f32;
+                let blurred = blur(&image, sigma);
+                return Ok(map_colors2(&image, &blurred, |original, blurred| {
+                    let mut sharpened = original;
+                    for channel in 0..3 {
+                        let difference = original[channel] as f32 - blurred[channel] as f32;
+                        if difference.abs() >= threshold {
+                            sharpened[channel] = (original[channel] as f32 + difference * amount)
+                                .round()
+                                .clamp(0.0, 255.0)
+                                as u8;
+                        }
+                    }
+                    sharpened
+                }));
+            }
+        }
+        Ok(image)
+    }
+}
+
+fn positive_sigma(sigma: f32) -> Result<f32, AppError> {
+    if sigma > 0.0 && sigma <= MAX_SIGMA {
+        Ok(sigma)
+    } else {
+        Err(AppError::InvalidTransform(format!(
+            "blur and sharpen need a sigma above 0 and at most {}",
+            MAX_SIGMA
+        )))
+    }
+}
+
+/// Gaussian blur of the premultiplied colors, so transparent pixels do not darken the colors
+/// next to them.
+fn blur(image: &RgbaImage, sigma: f32) -> RgbaImage {
+    // color times alpha and alpha times 255 fit into 16 bits without rounding
+    let premultiplied: ImageBuffer<Rgba<u16>, Vec<u16>> =
+        ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+            let Rgba([r, g, b, a]) = *image.get_pixel(x, y);
+            let a = a as u16;
+            Rgba([r as u16 * a, g as u16 * a, b as u16 * a, a * 255])
+        });
+    let blurred = gaussian_blur_f32(&premultiplied, sigma);
+    RgbaImage::from_fn(image.width(), image.height(), |x, y| {
+        let Rgba([r, g, b, a]) = *blurred.get_pixel(x, y);
+        if a == 0 {
+            return Rgba([0, 0, 0, 0]);
+        }
+        let channel = |value: u16| (value as f32 * 255.0 / a as f32).round().min(255.0) as u8;
+        Rgba([
+            channel(r),
+            channel(g),
+            channel(b),
+            (a as f32 / 255.0).round() as u8,
+        ])
+    })
+}
+
+/// Maps the color of every pixel in the range 0-1, alpha is kept.
+fn map_rgb(image: &mut RgbaImage, f: impl Fn([f32; 3]) -> [f32; 3]) {
+    map_colors_mut(image, |Rgba([r, g, b, a])| {
+        let [r, g, b] = f([r, g, b].map(|channel| channel as f32 / 255.0))
+            .map(|channel| (channel * 255.0).round().clamp(0.0, 255.0) as u8);
+        Rgba([r, g, b, a])
+    });
+}
+
+fn multiply(image: &mut RgbaImage, matrix: [[f32; 3]; 3]) {
+    map_rgb(image, |[r, g, b]| {
+        matrix.map(|row| row[0] * r + row[1] * g + row[2] * b)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A red square in the middle of a transparent image.
+    fn red_square() -> RgbaImage {
+        RgbaImage::from_fn(20, 20, |x, y| {
+            if (5..15).contains(&x) && (5..15).contains(&y) {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        })
+    }
+
+    #[test]
+    fn blur_keeps_colors_next_to_transparency() {
+        let blurred = blur(&red_square(), 2.0);
+        for pixel in [blurred[(5, 10)], blurred[(4, 10)], blurred[(2, 10)]] {
+            let Rgba([r, g, b, a]) = pixel;
+            assert!(a > 0 && a < 255);
+            assert_eq!([r, g, b], [255, 0, 0]);
+        }
+        assert_eq!(blurred[(10, 10)], Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn blur_of_opaque_image_keeps_flat_colors() {
+        let image = RgbaImage::from_pixel(10, 10, Rgba([10, 120, 240, 255]));
+        assert_eq!(blur(&image, 3.0), image);
+    }
+
+    #[test]
+    fn sigma_is_bounded() {
+        assert!(positive_sigma(0.0).is_err());
+        assert!(positive_sigma(MAX_SIGMA + 1.0).is_err());
+        assert!(positive_sigma(1e11).is_err());
+        assert!(positive_sigma(2.0).is_ok());
+    }
+}
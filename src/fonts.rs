@@ -0,0 +1,170 @@
+use std::{
+    collections::BTreeSet,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use axum::body::Bytes;
+use axum_typed_multipart::FieldData;
+use resvg::usvg::{
+    self,
+    fontdb::{self, Database, Source},
+    FontFamily, FontResolver,
+};
+
+use crate::{limits::Limits, AppError};
+
+pub struct FontSettings {
+    /// Directory with additional font files.
+    pub directory: Option<PathBuf>,
+    /// Whether the fonts installed on the host are loaded as well.
+    pub system_fonts: bool,
+    /// Family used for generic families and text without a font-family.
+    pub default_family: Option<String>,
+    /// Reject svgs that ask for a family which is not available.
+    pub strict: bool,
+}
+
+/// The font database and svg options shared by all requests.
+pub struct Fonts {
+    database: Arc<Database>,
+    default_family: String,
+    strict: bool,
+    limits: Limits,
+    shared: Arc<SvgOptions>,
+}
+
+impl Fonts {
+    pub fn load(settings: FontSettings, limits: Limits) -> Fonts {
+        let mut database = Database::new();
+        if settings.system_fonts {
+            database.load_system_fonts();
+        }
+        if let Some(directory) = &settings.directory {
+            database.load_fonts_dir(directory);
+        }
+
+        // without a configured family, fall back to any font we have instead of rendering no text
+        let default_family = settings
+            .default_family
+            .or_else(|| {
+                database
+                    .faces()
+                    .find_map(|face| face.families.first())
+                    .map(|(family, _)| family.clone())
+            })
+            .unwrap_or_else(|| usvg::Options::default().font_family);
+        database.set_serif_family(default_family.clone());
+        database.set_sans_serif_family(default_family.clone());
+        database.set_cursive_family(default_family.clone());
+        database.set_fantasy_family(default_family.clone());
+        database.set_monospace_family(default_family.clone());
+
+        let database = Arc::new(database);
+        let shared = Arc::new(SvgOptions {
+            options: base_options(&database, &default_family, limits),
+            missing: Default::default(),
+        });
+        Fonts {
+            database,
+            default_family,
+            strict: settings.strict,
+            limits,
+            shared,
+        }
+    }
+
+    /// Svg options without request fonts or font checks, used by every handler that parses svgs.
+    pub fn shared_options(&self) -> Arc<SvgOptions> {
+        self.shared.clone()
+    }
+
+    /// Svg options for one request, fonts uploaded with the request are only visible to it.
+    pub fn svg_options(
+        &self,
+        uploaded: &[FieldData<Bytes>],
+        strict: Option<bool>,
+    ) -> Result<Arc<SvgOptions>, AppError> {
+        let strict = strict.unwrap_or(self.strict);
+        if uploaded.is_empty() && !strict {
+            return Ok(self.shared_options());
+        }
+
+        let mut options = base_options(&self.database, &self.default_family, self.limits);
+        for font in uploaded {
+            let faces = options
+                .fontdb_mut()
+                .load_font_source(Source::Binary(Arc::new(font.contents.clone())));
+            if faces.is_empty() {
+                return Err(AppError::InvalidFont(
+                    font.metadata.file_name.clone().unwrap_or_default(),
+                ));
+            }
+        }
+
+        let missing = Arc::new(Mutex::new(BTreeSet::new()));
+        if strict {
+            let missing = missing.clone();
+            let select_font = FontResolver::default_font_selector();
+            options.font_resolver.select_font = Box::new(move |font, database| {
+                let available = font
+                    .families()
+                    .iter()
+                    .any(|family| has_family(database, family));
+                if !available {
+                    missing.lock().unwrap().extend(font.families().iter().map(
+                        |family| match family {
+                            FontFamily::Named(name) => name.clone(),
+                            generic => generic.to_string(),
+                        },
+                    ));
+                }
+                select_font(font, database)
+            });
+        }
+        Ok(Arc::new(SvgOptions { options, missing }))
+    }
+}
+
+fn base_options(
+    database: &Arc<Database>,
+    default_family: &str,
+    limits: Limits,
+) -> usvg::Options<'static> {
+    usvg::Options {
+        font_family: default_family.into(),
+        fontdb: database.clone(),
+        image_href_resolver: limits.image_href_resolver(),
+        ..Default::default()
+    }
+}
+
+fn has_family(database: &Database, family: &FontFamily) -> bool {
+    let family = match family {
+        // generic families always resolve to the default family
+        FontFamily::Named(name) => fontdb::Family::Name(name),
+        _ => return true,
+    };
+    database
+        .query(&fontdb::Query {
+            families: &[family],
+            ..Default::default()
+        })
+        .is_some()
+}
+
+pub struct SvgOptions {
+    pub options: usvg::Options<'static>,
+    missing: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl SvgOptions {
+    /// Fails if a strict parse asked for font families that are not available.
+    pub fn check_missing_fonts(&self) -> Result<(), AppError> {
+        let missing = self.missing.lock().unwrap();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        Err(AppError::MissingFont(missing.iter().cloned().collect()))
+    }
+}
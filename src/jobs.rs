@@ -0,0 +1,51 @@
+use std::sync::Arc;
+
+use tokio::{sync::Semaphore, task};
+
+use crate::AppError;
+
+/// Runs the decoding, rendering and encoding on the blocking thread pool, so they do not stall
+/// the async workers, and bounds how many of them run and wait at once.
+pub struct Jobs {
+    /// Permits for the running and the queued jobs, requests beyond them are turned away.
+    admitted: Arc<Semaphore>,
+    /// Permits for the running jobs.
+    running: Arc<Semaphore>,
+}
+
+impl Jobs {
+    pub fn new(max_jobs: usize, max_queued_jobs: usize) -> Jobs {
+        Jobs {
+            admitted: Arc::new(Semaphore::new(max_jobs + max_queued_jobs)),
+            running: Arc::new(Semaphore::new(max_jobs)),
+        }
+    }
+
+    /// Waits for a free slot and runs `job` on the blocking pool, fails with `Overloaded` right
+    /// away when the queue is full.
+    pub async fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> Result<T, AppError> + Send + 'static,
+    ) -> Result<T, AppError> {
+        let admitted = self
+            .admitted
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| AppError::Overloaded)?;
+        let running = self
+            .running
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        // the permits stay with the job, a client that hangs up does not free its slot early
+        // a panic is already printed by the panic hook, the client gets a 500 instead of a
+        // dropped connection
+        task::spawn_blocking(move || {
+            let _permits = (admitted, running);
+            job()
+        })
+        .await
+        .map_err(|_| AppError::JobFailed)?
+    }
+}
@@ -0,0 +1,552 @@
+use std::{collections::HashMap, io::Cursor};
+
+use axum::body::Bytes;
+use axum_typed_multipart::{FieldData, TryFromField};
+use imageproc::image::{
+    imageops::FilterType, metadata::Orientation, DynamicImage, GrayImage, ImageDecoder,
+    ImageFormat, ImageReader,
+};
+use resvg::tiny_skia::{IntSize, Size, Transform};
+use serde::Deserialize;
+
+use crate::{
+    blend::{composite, BlendMode},
+    filter::{apply_filters, Filter},
+    fonts::SvgOptions,
+    limits::Limits,
+    mask::{apply_mask, Mask},
+    metadata::SourceMetadata,
+    svg::{parse_svg, render_svg, substitute_variables, ViewBox},
+    tile::Tile,
+    AppError,
+};
+
+/// Placement of a single layer, sent as a json part next to the layer.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct LayerMetadata {
+    /// Offset from the anchor point in pixels.
+    pub x: i64,
+    pub y: i64,
+    /// Size of the box the layer is fitted into, defaults to the size of the base image or for
+    /// tiled layers to the size of the layer itself, scaled to the given side.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub anchor: Anchor,
+    /// Defaults to `preserve-aspect-ratio` for svgs and `contain` for images.
+    pub fit: Option<Fit>,
+    /// Overrides the resampling filter of the request.
+    pub resampling: Option<Resampling>,
+    /// Opacity from 0 (invisible) to 1 (opaque).
+    pub opacity: Option<f32>,
+    pub blend: BlendMode,
+    /// Applied to the layer before it is drawn.
+    pub filters: Vec<Filter>,
+    pub mask: Option<Mask>,
+    /// Repeats the layer over the whole image, the anchor and offset place one of the tiles.
+    pub tile: Option<Tile>,
+    /// Stacking order, the base image is at 0 and drawn before the layers with the same z-index.
+    /// Layers with a negative z-index are drawn beneath it.
+    pub z_index: i32,
+}
+
+impl LayerMetadata {
+    /// Filters, tiles and masks the rendered layer and places it on a canvas of the given size.
+    pub fn place(
+        &self,
+        image: DynamicImage,
+        canvas: IntSize,
+        masks: &HashMap<String, FieldData<Bytes>>,
+        svg_options: &SvgOptions,
+        resampling: Resampling,
+        limits: Limits,
+    ) -> Result<PlacedLayer, AppError> {
+        let image = apply_filters(image, &self.filters)?;
+        let (image, x, y) = match &self.tile {
+            Some(tile) => (
+                tile.fill(&image, canvas, self.anchor, (self.x, self.y), limits)?,
+                0,
+                0,
+            ),
+            None => {
+                let (x, y) = self
+                    .anchor
+                    .align((image.width(), image.height()), canvas.dimensions());
+                (image, x + self.x, y + self.y)
+            }
+        };
+        let mask = self.render_mask(&image, masks, svg_options, resampling, limits)?;
+        Ok(PlacedLayer {
+            image,
+            x,
+            y,
+            opacity: self.opacity.unwrap_or(1.0),
+            blend: self.blend,
+            mask,
+            z_index: self.z_index,
+        })
+    }
+
+    /// Size of the box the layer is fitted into, `natural` is the size of the layer itself.
+    fn target(&self, natural: IntSize, canvas: IntSize) -> Result<IntSize, AppError> {
+        let target = match (self.width, self.height, &self.tile) {
+            // a tile with one side given keeps its aspect ratio
+            (Some(width), None, Some(_)) => natural.scale_to_width(width),
+            (None, Some(height), Some(_)) => natural.scale_to_height(height),
+            (width, height, tile) => {
+                let default = if tile.is_some() { natural } else { canvas };
+                IntSize::from_wh(
+                    width.unwrap_or(default.width()),
+                    height.unwrap_or(default.height()),
+                )
+            }
+        };
+        target.ok_or(AppError::InvalidSize)
+    }
+
+    /// Renders the mask of the layer at the size of the prepared `image`.
+    fn render_mask(
+        &self,
+        image: &DynamicImage,
+        masks: &HashMap<String, FieldData<Bytes>>,
+        svg_options: &SvgOptions,
+        resampling: Resampling,
+        limits: Limits,
+    ) -> Result<Option<GrayImage>, AppError> {
+        let Some(mask) = &self.mask else {
+            return Ok(None);
+        };
+        let part = masks
+            .get(&mask.source)
+            .ok_or(AppError::MissingMask(mask.source.clone()))?;
+        let size = IntSize::from_wh(image.width(), image.height()).ok_or(AppError::InvalidSize)?;
+        mask.render(
+            part,
+            size,
+            svg_options,
+            self.resampling.unwrap_or(resampling),
+            limits,
+        )
+        .map(Some)
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Anchor {
+    #[default]
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+    /// Horizontal and vertical position of the anchor point, 0 is left/top and 1 is right/bottom.
+    fn factors(self) -> (f32, f32) {
+        match self {
+            Anchor::TopLeft => (0.0, 0.0),
+            Anchor::Top => (0.5, 0.0),
+            Anchor::TopRight => (1.0, 0.0),
+            Anchor::Left => (0.0, 0.5),
+            Anchor::Center => (0.5, 0.5),
+            Anchor::Right => (1.0, 0.5),
+            Anchor::BottomLeft => (0.0, 1.0),
+            Anchor::Bottom => (0.5, 1.0),
+            Anchor::BottomRight => (1.0, 1.0),
+        }
+    }
+
+    /// Offset that aligns an item of size `inner` inside of `outer` at this anchor.
+    pub fn align(self, inner: (u32, u32), outer: (u32, u32)) -> (i64, i64) {
+        let (fx, fy) = self.factors();
+        (
+            ((outer.0 as f32 - inner.0 as f32) * fx).round() as i64,
+            ((outer.1 as f32 - inner.1 as f32) * fy).round() as i64,
+        )
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Fit {
+    /// Lay the svg out in the box according to its `preserveAspectRatio`, images use `contain`.
+    PreserveAspectRatio,
+    /// Scale the layer to fit inside the box, keeping the aspect ratio.
+    Contain,
+    /// Scale the layer to fill the box, keeping the aspect ratio and cropping the overflow.
+    Cover,
+    /// Scale the layer to exactly the size of the box.
+    Stretch,
+    /// Keep the size of the layer.
+    None,
+}
+
+impl Fit {
+    pub fn size(self, content: IntSize, target: IntSize) -> IntSize {
+        match self {
+            Fit::PreserveAspectRatio | Fit::Contain => {
+                content.to_size().scale_to(target.to_size()).to_int_size()
+            }
+            Fit::Cover => content.to_size().expand_to(target.to_size()).to_int_size(),
+            Fit::Stretch => target,
+            Fit::None => content,
+        }
+    }
+
+    /// Size of the pixmap and the transform that fits the svg into it.
+    fn svg_layout(
+        self,
+        tree_size: Size,
+        view_box: impl FnOnce() -> ViewBox,
+        target: IntSize,
+        anchor: Anchor,
+    ) -> (IntSize, Transform) {
+        let scale_to = |size: IntSize| {
+            Transform::from_scale(
+                size.width() as f32 / tree_size.width(),
+                size.height() as f32 / tree_size.height(),
+            )
+        };
+        match self {
+            Fit::PreserveAspectRatio => {
+                (target, view_box().transform_to(tree_size, target.to_size()))
+            }
+            Fit::Cover => {
+                // render straight into the box instead of cropping a larger pixmap
+                let scaled = tree_size.expand_to(target.to_size());
+                let (fx, fy) = anchor.factors();
+                let transform = Transform::from_row(
+                    scaled.width() / tree_size.width(),
+                    0.0,
+                    0.0,
+                    scaled.height() / tree_size.height(),
+                    (target.width() as f32 - scaled.width()) * fx,
+                    (target.height() as f32 - scaled.height()) * fy,
+                );
+                (target, transform)
+            }
+            Fit::Contain | Fit::Stretch | Fit::None => {
+                let size = self.size(tree_size.to_int_size(), target);
+                (size, scale_to(size))
+            }
+        }
+    }
+}
+
+/// Filter used when an image layer has to be resized.
+#[derive(Deserialize, TryFromField, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+#[try_from_field(rename_all = "kebab-case")]
+pub enum Resampling {
+    Nearest,
+    Triangle,
+    #[default]
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl From<Resampling> for FilterType {
+    fn from(resampling: Resampling) -> Self {
+        match resampling {
+            Resampling::Nearest => FilterType::Nearest,
+            Resampling::Triangle => FilterType::Triangle,
+            Resampling::CatmullRom => FilterType::CatmullRom,
+            Resampling::Gaussian => FilterType::Gaussian,
+            Resampling::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// A prepared layer and how it is drawn onto the image.
+pub struct PlacedLayer {
+    pub image: DynamicImage,
+    pub x: i64,
+    pub y: i64,
+    pub opacity: f32,
+    pub blend: BlendMode,
+    /// Coverage of each pixel of `image`.
+    pub mask: Option<GrayImage>,
+    pub z_index: i32,
+}
+
+impl PlacedLayer {
+    pub fn draw_onto(&self, image: &mut DynamicImage) {
+        match &self.mask {
+            Some(mask) => composite(
+                image,
+                &apply_mask(&self.image, mask),
+                self.x,
+                self.y,
+                self.blend,
+                self.opacity,
+            ),
+            None => composite(image, &self.image, self.x, self.y, self.blend, self.opacity),
+        }
+    }
+}
+
+/// Draws layers one at a time, the ones with a z-index below 0 beneath the image.
+///
+/// The layers have to be pushed in the order of their z-index, so none of them has to be kept
+/// until the end.
+pub struct LayerStack {
+    /// The image until the first layer at or above 0 is pushed.
+    image: Option<DynamicImage>,
+    /// What has been drawn so far, transparent beneath the image.
+    canvas: Option<DynamicImage>,
+    width: u32,
+    height: u32,
+}
+
+impl LayerStack {
+    pub fn new(image: DynamicImage) -> LayerStack {
+        LayerStack {
+            width: image.width(),
+            height: image.height(),
+            image: Some(image),
+            canvas: None,
+        }
+    }
+
+    pub fn push(&mut self, layer: PlacedLayer) {
+        if layer.z_index >= 0 {
+            self.draw_image();
+        }
+        let (width, height) = (self.width, self.height);
+        let canvas = self
+            .canvas
+            .get_or_insert_with(|| DynamicImage::new_rgba8(width, height));
+        layer.draw_onto(canvas);
+    }
+
+    pub fn finish(mut self) -> DynamicImage {
+        self.draw_image();
+        self.canvas.expect("the image is drawn")
+    }
+
+    fn draw_image(&mut self) {
+        let Some(image) = self.image.take() else {
+            return;
+        };
+        self.canvas = Some(match self.canvas.take() {
+            Some(mut canvas) => {
+                composite(&mut canvas, &image, 0, 0, BlendMode::Normal, 1.0);
+                canvas
+            }
+            None => image,
+        });
+    }
+}
+
+pub fn is_svg(field: &FieldData<Bytes>) -> bool {
+    field.metadata.content_type.as_deref() == Some("image/svg+xml")
+}
+
+fn image_reader(
+    field: &FieldData<Bytes>,
+    limits: Limits,
+) -> Result<ImageReader<Cursor<Bytes>>, AppError> {
+    let mut image_reader = ImageReader::new(Cursor::new(field.contents.clone()));
+    let mimetype = field.metadata.content_type.as_ref();
+    let unwraped_mimetype = mimetype.ok_or(AppError::MissingMimeType)?;
+    image_reader.set_format(
+        ImageFormat::from_mime_type(unwraped_mimetype)
+            .ok_or(AppError::InvalidMimeType(unwraped_mimetype.into()))?,
+    );
+    image_reader.limits(limits.decoder());
+    Ok(image_reader)
+}
+
+pub fn decode_image(field: &FieldData<Bytes>, limits: Limits) -> Result<DynamicImage, AppError> {
+    let decoder = image_reader(field, limits)?
+        .into_decoder()
+        .map_err(|err| limits.decoding_error(err))?;
+    let (width, height) = decoder.dimensions();
+    limits.check_decoded(width, height)?;
+    DynamicImage::from_decoder(decoder).map_err(|err| limits.decoding_error(err))
+}
+
+/// Decodes the base image with its metadata, `auto_orient` turns it upright according to its
+/// EXIF orientation.
+pub fn decode_base_image(
+    field: &FieldData<Bytes>,
+    auto_orient: bool,
+    limits: Limits,
+) -> Result<(DynamicImage, SourceMetadata), AppError> {
+    let mut decoder = image_reader(field, limits)?
+        .into_decoder()
+        .map_err(|err| limits.decoding_error(err))?;
+    let (width, height) = decoder.dimensions();
+    limits.check_decoded(width, height)?;
+    // broken metadata is no reason to reject the photo
+    let metadata = SourceMetadata {
+        icc_profile: decoder.icc_profile().ok().flatten(),
+        exif: decoder.exif_metadata().ok().flatten(),
+    };
+    let orientation = if auto_orient {
+        decoder.orientation().unwrap_or(Orientation::NoTransforms)
+    } else {
+        Orientation::NoTransforms
+    };
+    let mut image =
+        DynamicImage::from_decoder(decoder).map_err(|err| limits.decoding_error(err))?;
+    image.apply_orientation(orientation);
+    Ok((image, metadata))
+}
+
+pub fn prepare_layers<'a>(
+    image_witdh: u32,
+    image_height: u32,
+    variables: &'a HashMap<String, String>,
+    svg_options: &'a SvgOptions,
+    resampling: Resampling,
+    masks: &'a HashMap<String, FieldData<Bytes>>,
+    limits: Limits,
+) -> impl Fn((&FieldData<Bytes>, &LayerMetadata)) -> Result<PlacedLayer, AppError> + Send + Sync + 'a
+{
+    move |(layer, metadata)| {
+        let canvas = IntSize::from_wh(image_witdh, image_height).ok_or(AppError::InvalidSize)?;
+
+        let overlay_image = if is_svg(layer) {
+            let fit = metadata.fit.unwrap_or(Fit::PreserveAspectRatio);
+            let data = substitute_variables(&layer.contents, variables)?;
+            let tree = parse_svg(&data, svg_options)?;
+            let target = metadata.target(tree.size().to_int_size(), canvas)?;
+            let (size, transform) = fit.svg_layout(
+                tree.size(),
+                || ViewBox::read(&data, tree.size()),
+                target,
+                metadata.anchor,
+            );
+            limits.check_output(size.width(), size.height())?;
+            render_svg(&tree, size, transform)?
+        } else {
+            let fit = metadata.fit.unwrap_or(Fit::Contain);
+            let image = decode_image(layer, limits)?;
+            let original_size =
+                IntSize::from_wh(image.width(), image.height()).ok_or(AppError::InvalidSize)?;
+            let target = metadata.target(original_size, canvas)?;
+            let size = fit.size(original_size, target);
+            limits.check_output(size.width(), size.height())?;
+            let mut image = if size == original_size {
+                image
+            } else {
+                image.resize_exact(
+                    size.width(),
+                    size.height(),
+                    metadata.resampling.unwrap_or(resampling).into(),
+                )
+            };
+            if let Fit::Cover = fit {
+                let (x, y) = metadata
+                    .anchor
+                    .align(target.dimensions(), (image.width(), image.height()));
+                image = image.crop_imm(
+                    x.max(0) as u32,
+                    y.max(0) as u32,
+                    target.width(),
+                    target.height(),
+                );
+            }
+            image
+        };
+        metadata.place(
+            overlay_image,
+            canvas,
+            masks,
+            svg_options,
+            resampling,
+            limits,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use imageproc::image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const GREEN: Rgba<u8> = Rgba([0, 255, 0, 255]);
+    const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+    const TRANSPARENT: Rgba<u8> = Rgba([0, 0, 0, 0]);
+
+    fn layer(color: Rgba<u8>, z_index: i32) -> PlacedLayer {
+        PlacedLayer {
+            image: DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 1, color)),
+            x: 0,
+            y: 0,
+            opacity: 1.0,
+            blend: BlendMode::Normal,
+            mask: None,
+            z_index,
+        }
+    }
+
+    /// A transparent pixel next to a blue one.
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(2, 1, |x, _| {
+            if x == 0 {
+                TRANSPARENT
+            } else {
+                BLUE
+            }
+        }))
+    }
+
+    fn pixels(image: DynamicImage) -> Vec<Rgba<u8>> {
+        image.to_rgba8().pixels().copied().collect()
+    }
+
+    #[test]
+    fn without_layers_keeps_image() {
+        assert_eq!(pixels(LayerStack::new(image()).finish()), pixels(image()));
+    }
+
+    #[test]
+    fn negative_layer_is_beneath_image() {
+        let mut stack = LayerStack::new(image());
+        stack.push(layer(RED, -1));
+        assert_eq!(pixels(stack.finish()), [RED, BLUE]);
+    }
+
+    #[test]
+    fn layer_at_zero_is_over_image() {
+        let mut stack = LayerStack::new(image());
+        stack.push(layer(RED, 0));
+        assert_eq!(pixels(stack.finish()), [RED, RED]);
+    }
+
+    #[test]
+    fn layers_on_both_sides_of_image() {
+        let mut stack = LayerStack::new(image());
+        stack.push(layer(RED, -2));
+        stack.push(layer(GREEN, -1));
+        let mut above = layer(RED, 1);
+        above.image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, RED));
+        above.x = 1;
+        stack.push(above);
+        assert_eq!(pixels(stack.finish()), [GREEN, RED]);
+    }
+
+    #[test]
+    fn equal_z_index_keeps_push_order() {
+        let mut stack = LayerStack::new(image());
+        stack.push(layer(RED, 1));
+        stack.push(layer(GREEN, 1));
+        assert_eq!(pixels(stack.finish()), [GREEN, GREEN]);
+
+        let mut stack = LayerStack::new(image());
+        stack.push(layer(GREEN, -1));
+        stack.push(layer(RED, -1));
+        assert_eq!(pixels(stack.finish()), [RED, BLUE]);
+    }
+}
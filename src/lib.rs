@@ -0,0 +1,636 @@
+use std::{any::type_name, collections::HashMap, io, process, sync::Arc};
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{DefaultBodyLimit, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Router,
+};
+use axum_typed_multipart::{
+    FieldData, FieldMetadata, TryFromChunks, TryFromMultipart, TypedMultipart, TypedMultipartError,
+};
+use canvas::Canvas;
+use config::{Config, ConfigError};
+use filter::{apply_filters, Filter};
+use fonts::Fonts;
+use futures_util::stream::Stream;
+use imageproc::image::ImageError;
+use jobs::Jobs;
+use layer::{decode_base_image, prepare_layers, Fit, LayerMetadata, LayerStack, Resampling};
+use metadata::{MetadataOptions, SourceMetadata};
+use output::{encode, image_response, OutputFormat};
+use pipeline::run_pipeline;
+use rayon::prelude::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use templates::{delete_template, list_templates, upload_template, TemplateStore};
+use text::TextLayer;
+use transform::{apply_transforms, Operation};
+
+mod blend;
+mod canvas;
+pub mod config;
+mod filter;
+pub mod fonts;
+mod jobs;
+mod layer;
+pub mod limits;
+mod mask;
+mod metadata;
+mod output;
+mod pipeline;
+pub mod svg;
+mod templates;
+mod text;
+mod tile;
+mod transform;
+
+/// Seconds a client is asked to wait when the server is overloaded.
+const RETRY_AFTER_SECONDS: u64 = 1;
+
+#[derive(TryFromMultipart)]
+struct TransformRequest {
+    image: Option<FieldData<Bytes>>,
+    /// Size and background of an empty image to draw on, instead of sending an image.
+    canvas: Option<Json<Canvas>>,
+    /// Turns the image upright according to its EXIF orientation, defaults to true.
+    auto_orient: Option<bool>,
+    /// Operations applied to the image before the layers are drawn.
+    transforms: Option<Json<Vec<Operation>>>,
+    /// Color adjustments and blurs applied to the image after the transforms.
+    filters: Option<Json<Vec<Filter>>>,
+    layers: Vec<FieldData<Bytes>>,
+    layer_metadata: Vec<Json<LayerMetadata>>,
+    sticker: Vec<FieldData<Bytes>>,
+    sticker_metadata: Vec<Json<StickerMetadata>>,
+    /// Captions, stacked with the layers and stickers by their z_index.
+    text: Vec<Json<TextLayer>>,
+    /// Masks of the layers, referenced by their file name.
+    masks: Vec<FieldData<Bytes>>,
+    /// Overrides the format negotiated from the `Accept` header.
+    format: Option<OutputFormat>,
+    /// Values for the placeholders in svg layers.
+    variables: Option<Json<HashMap<String, String>>>,
+    /// Filter for resizing image layers, defaults to catmull-rom.
+    resampling: Option<Resampling>,
+    /// Font files only available to the svg layers of this request.
+    fonts: Vec<FieldData<Bytes>>,
+    /// Overrides whether missing font families are an error.
+    strict_fonts: Option<bool>,
+    quality: Option<u8>,
+    /// Color profile and EXIF fields written into the output.
+    metadata: Option<Json<MetadataOptions>>,
+}
+
+/// A form field whose contents are parsed as JSON.
+struct Json<T>(T);
+
+#[async_trait]
+impl<T: DeserializeOwned> TryFromChunks for Json<T> {
+    async fn try_from_chunks(
+        chunks: impl Stream<Item = Result<Bytes, TypedMultipartError>> + Send + Sync + Unpin,
+        metadata: FieldMetadata,
+    ) -> Result<Self, TypedMultipartError> {
+        let field_name = metadata.name.clone().unwrap_or_default();
+        let contents = String::try_from_chunks(chunks, metadata).await?;
+        serde_json::from_str(&contents).map(Json).map_err(|err| {
+            TypedMultipartError::WrongFieldType {
+                field_name,
+                wanted_type: type_name::<T>().into(),
+                source: err.into(),
+            }
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct StickerMetadata {
+    position: Position,
+}
+
+#[derive(Deserialize)]
+struct Position {
+    x: i64,
+    y: i64,
+}
+
+impl From<&StickerMetadata> for LayerMetadata {
+    fn from(sticker: &StickerMetadata) -> Self {
+        LayerMetadata {
+            x: sticker.position.x,
+            y: sticker.position.y,
+            fit: Some(Fit::None),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    config: Arc<Config>,
+    jobs: Arc<Jobs>,
+    templates: Arc<TemplateStore>,
+    fonts: Arc<Fonts>,
+}
+
+/// Reads the configuration and serves requests until the process is stopped.
+pub fn run() {
+    let (config, templates) = Config::load()
+        .and_then(|config| {
+            let templates = TemplateStore::load(&config.template_dir)
+                .map_err(|err| ConfigError::Read(config.template_dir.clone(), err))?;
+            Ok((config, templates))
+        })
+        .unwrap_or_else(|err| {
+            eprintln!("invalid configuration: {}", err);
+            process::exit(1);
+        });
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(worker_threads) = config.worker_threads {
+        runtime.worker_threads(worker_threads);
+    }
+    let runtime = runtime
+        .enable_all()
+        .build()
+        .expect("failed to start the async runtime");
+    runtime.block_on(serve(config, templates));
+}
+
+async fn serve(config: Config, templates: TemplateStore) {
+    let address = config.address();
+    let max_upload_size = config.max_upload_size;
+    let state = AppState {
+        jobs: Arc::new(Jobs::new(config.max_jobs(), config.max_queued_jobs)),
+        templates: Arc::new(templates),
+        fonts: Arc::new(Fonts::load(config.font_settings(), config.limits())),
+        config: Arc::new(config),
+    };
+
+    let app = Router::new()
+        .route("/", post(create_document))
+        .route("/pipeline", post(run_pipeline))
+        .route("/templates", get(list_templates))
+        .route(
+            "/templates/:name",
+            put(upload_template).delete(delete_template),
+        )
+        .layer(DefaultBodyLimit::max(max_upload_size))
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(address)
+        .await
+        .unwrap_or_else(|err| {
+            eprintln!("cannot listen on {}: {}", address, err);
+            process::exit(1);
+        });
+    axum::serve(listener, app).await.expect("server failed");
+}
+pub enum AppError {
+    MissingMimeType,
+    InvalidMimeType(String),
+    DecodingFailure(ImageError),
+    EncodingFailure,
+    SvgParserFailure,
+    InvalidSize,
+    InvalidTransform(String),
+    MissingStickerMetadata,
+    UnmatchedLayerMetadata,
+    TemplateNotFound(String),
+    InvalidTemplateName(String),
+    TemplateStorageFailure(io::Error),
+    InvalidFont(String),
+    MissingFont(Vec<String>),
+    InvalidPipeline(String),
+    MissingPart(String),
+    MissingMask(String),
+    InvalidCanvas(String),
+    /// Rejections of the extractors other than the body limit, answered as before.
+    Rejection(Box<Response>),
+    PayloadTooLarge(usize),
+    ImageTooLarge(String),
+    OutputTooLarge(String),
+    TooManyLayers(usize),
+    TooManySteps(usize),
+    TextTooLong(usize),
+    SvgTooLarge(usize),
+    /// All jobs are running and the queue is full.
+    Overloaded,
+    /// A job panicked or was cancelled.
+    JobFailed,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        // How we want errors responses to be serialized
+        #[derive(Serialize)]
+        struct ErrorResponse {
+            #[serde(rename = "type")]
+            //error_type: String,
+            //status: i32,
+            title: String,
+            details: String,
+            //instance: String,
+        }
+
+        let (status, message) = match self {
+            AppError::DecodingFailure(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorResponse {
+                    title: "Decoding-Error".into(),
+                    details: format!("Failed to decode one of the overlays ({})", err),
+                },
+            ),
+            AppError::MissingMimeType => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    title: "MimeType-Error".into(),
+                    details: "Missing mime type for one of the overlays".into(),
+                },
+            ),
+            AppError::InvalidMimeType(mime_type) => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    title: "MimeType-Error".into(),
+                    details: format!("Invalid mime type ({})for one of the overlays", mime_type),
+                },
+            ),
+            AppError::EncodingFailure => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorResponse {
+                    title: "Encoding-Error".into(),
+                    details: "Failed to encode the image".into(),
+                },
+            ),
+            AppError::SvgParserFailure => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorResponse {
+                    title: "Svg-Error".into(),
+                    details: "Failed to parse one of the svg-overlays".into(),
+                },
+            ),
+            AppError::InvalidSize => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    title: "Transform-Error".into(),
+                    details: "The image or overlay has an invalid size".into(),
+                },
+            ),
+            AppError::InvalidTransform(details) => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    title: "Transform-Error".into(),
+                    details,
+                },
+            ),
+            AppError::MissingStickerMetadata => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    title: "Sticker-Error".into(),
+                    details: "Every sticker needs a matching sticker_metadata field".into(),
+                },
+            ),
+            AppError::UnmatchedLayerMetadata => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    title: "Layer-Error".into(),
+                    details: "There are more layer_metadata fields than layers".into(),
+                },
+            ),
+            AppError::TemplateNotFound(name) => (
+                StatusCode::NOT_FOUND,
+                ErrorResponse {
+                    title: "Template-Error".into(),
+                    details: format!("There is no template named {}", name),
+                },
+            ),
+            AppError::InvalidTemplateName(name) => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    title: "Template-Error".into(),
+                    details: format!(
+                        "Invalid template name ({}), only letters, digits, - and _ are allowed",
+                        name
+                    ),
+                },
+            ),
+            AppError::TemplateStorageFailure(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorResponse {
+                    title: "Template-Error".into(),
+                    details: format!("Failed to store the template ({})", err),
+                },
+            ),
+            AppError::InvalidFont(file_name) => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    title: "Font-Error".into(),
+                    details: format!("Failed to load the font {}", file_name),
+                },
+            ),
+            AppError::MissingFont(families) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorResponse {
+                    title: "Font-Error".into(),
+                    details: format!("Missing font families: {}", families.join(", ")),
+                },
+            ),
+            AppError::InvalidPipeline(details) => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    title: "Pipeline-Error".into(),
+                    details,
+                },
+            ),
+            AppError::MissingPart(name) => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    title: "Pipeline-Error".into(),
+                    details: format!("There is no part named {}", name),
+                },
+            ),
+            AppError::MissingMask(name) => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    title: "Mask-Error".into(),
+                    details: format!("There is no mask named {}", name),
+                },
+            ),
+            AppError::InvalidCanvas(details) => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    title: "Canvas-Error".into(),
+                    details,
+                },
+            ),
+            AppError::Rejection(response) => return *response,
+            AppError::PayloadTooLarge(limit) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                ErrorResponse {
+                    title: "Limit-Error".into(),
+                    details: format!("The request is larger than {} bytes", limit),
+                },
+            ),
+            AppError::ImageTooLarge(details) | AppError::OutputTooLarge(details) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorResponse {
+                    title: "Limit-Error".into(),
+                    details,
+                },
+            ),
+            AppError::Overloaded => {
+                let message = ErrorResponse {
+                    title: "Overloaded-Error".into(),
+                    details: "The server is busy, try again later".into(),
+                };
+                return (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    [(header::RETRY_AFTER, RETRY_AFTER_SECONDS.to_string())],
+                    axum::Json(message),
+                )
+                    .into_response();
+            }
+            AppError::TooManyLayers(limit) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorResponse {
+                    title: "Limit-Error".into(),
+                    details: format!("A request can have at most {} layers", limit),
+                },
+            ),
+            AppError::TooManySteps(limit) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorResponse {
+                    title: "Limit-Error".into(),
+                    details: format!(
+                        "A pipeline, transforms or filters list can have at most {} steps",
+                        limit
+                    ),
+                },
+            ),
+            AppError::TextTooLong(limit) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorResponse {
+                    title: "Limit-Error".into(),
+                    details: format!("A text can have at most {} characters", limit),
+                },
+            ),
+            AppError::SvgTooLarge(limit) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorResponse {
+                    title: "Limit-Error".into(),
+                    details: format!(
+                        "An svg with its variables filled in can have at most {} bytes",
+                        limit
+                    ),
+                },
+            ),
+            AppError::JobFailed => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorResponse {
+                    title: "Internal-Error".into(),
+                    details: "The image could not be processed".into(),
+                },
+            ),
+        };
+
+        (status, axum::Json(message)).into_response()
+    }
+}
+
+/// Fails when one of the lists of transforms, filters or pipeline steps is longer than
+/// `max_steps`, every step can take a while on a large image.
+fn check_steps(mut counts: impl Iterator<Item = usize>, config: &Config) -> Result<(), AppError> {
+    if counts.any(|count| count > config.max_steps) {
+        return Err(AppError::TooManySteps(config.max_steps));
+    }
+    Ok(())
+}
+
+async fn create_document(
+    State(state): State<AppState>,
+    request_headers: HeaderMap,
+    payload: Result<TypedMultipart<TransformRequest>, TypedMultipartError>,
+) -> Result<impl IntoResponse, AppError> {
+    let payload = payload.map_err(|err| {
+        if err.get_status() == StatusCode::PAYLOAD_TOO_LARGE {
+            AppError::PayloadTooLarge(state.config.max_upload_size)
+        } else {
+            AppError::Rejection(Box::new(err.into_response()))
+        }
+    })?;
+    let layer_count = payload.layers.len() + payload.sticker.len() + payload.text.len();
+    if layer_count > state.config.max_layers {
+        return Err(AppError::TooManyLayers(state.config.max_layers));
+    }
+    let step_counts = payload
+        .transforms
+        .iter()
+        .map(|Json(operations)| operations.len())
+        .chain(payload.filters.iter().map(|Json(filters)| filters.len()))
+        .chain(
+            payload
+                .layer_metadata
+                .iter()
+                .map(|Json(metadata)| metadata.filters.len()),
+        )
+        .chain(
+            payload
+                .text
+                .iter()
+                .map(|Json(text)| text.placement.filters.len()),
+        );
+    check_steps(step_counts, &state.config)?;
+    let format = match payload.format {
+        Some(format) => format,
+        None => OutputFormat::negotiate(&request_headers, state.config.default_format),
+    };
+    let jobs = state.jobs.clone();
+    let encoded = jobs
+        .run(move || compose_document(&state, payload.0, format))
+        .await?;
+    Ok(image_response(encoded, format))
+}
+
+/// A layer, sticker or text of a request.
+enum Source<'a> {
+    Image(&'a FieldData<Bytes>, &'a LayerMetadata),
+    Text(&'a TextLayer),
+}
+
+impl Source<'_> {
+    fn z_index(&self) -> i32 {
+        match self {
+            Source::Image(_, metadata) => metadata.z_index,
+            Source::Text(text) => text.placement.z_index,
+        }
+    }
+}
+
+/// Decodes, composes and encodes the image of a request, runs on the blocking pool.
+fn compose_document(
+    state: &AppState,
+    payload: TransformRequest,
+    format: OutputFormat,
+) -> Result<Vec<u8>, AppError> {
+    let limits = state.config.limits();
+    let resampling = payload.resampling.unwrap_or_default();
+    let (image, source_metadata) = match (&payload.image, &payload.canvas) {
+        (Some(image), None) => {
+            decode_base_image(image, payload.auto_orient.unwrap_or(true), limits)?
+        }
+        (None, Some(Json(canvas))) => (
+            canvas.render(&state.fonts.shared_options(), limits)?,
+            SourceMetadata::default(),
+        ),
+        _ => {
+            return Err(AppError::InvalidCanvas(
+                "send either an image or a canvas".into(),
+            ))
+        }
+    };
+    let image = match &payload.transforms {
+        Some(Json(operations)) => apply_transforms(image, operations, resampling, limits)?,
+        None => image,
+    };
+    let image = match &payload.filters {
+        Some(Json(filters)) => apply_filters(image, filters)?,
+        None => image,
+    };
+
+    if payload.layer_metadata.len() > payload.layers.len() {
+        return Err(AppError::UnmatchedLayerMetadata);
+    }
+    if payload.sticker.len() != payload.sticker_metadata.len() {
+        return Err(AppError::MissingStickerMetadata);
+    }
+    let svg_options = state
+        .fonts
+        .svg_options(&payload.fonts, payload.strict_fonts)?;
+    let no_variables = HashMap::new();
+    let variables = payload
+        .variables
+        .as_ref()
+        .map_or(&no_variables, |Json(variables)| variables);
+    let masks: HashMap<String, FieldData<Bytes>> = payload
+        .masks
+        .iter()
+        .filter_map(|mask| {
+            let name = mask.metadata.file_name.clone()?;
+            Some((
+                name,
+                FieldData {
+                    metadata: mask.metadata.clone(),
+                    contents: mask.contents.clone(),
+                },
+            ))
+        })
+        .collect();
+    let default_metadata = LayerMetadata::default();
+    let layer_metadata = payload
+        .layer_metadata
+        .iter()
+        .map(|Json(metadata)| metadata)
+        .chain(std::iter::repeat(&default_metadata));
+    let sticker_metadata: Vec<LayerMetadata> = payload
+        .sticker_metadata
+        .iter()
+        .map(|Json(metadata)| metadata.into())
+        .collect();
+
+    let layers = payload
+        .layers
+        .iter()
+        .map(|layer| state.templates.resolve(layer))
+        .collect::<Result<Vec<_>, _>>()?;
+    let stickers = payload
+        .sticker
+        .iter()
+        .map(|sticker| state.templates.resolve(sticker))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // a stable sort, sources with the same z-index keep the order of the form
+    let mut sources: Vec<_> = layers
+        .iter()
+        .zip(layer_metadata)
+        .chain(stickers.iter().zip(sticker_metadata.iter()))
+        .map(|(layer, metadata)| Source::Image(layer, metadata))
+        .chain(payload.text.iter().map(|Json(text)| Source::Text(text)))
+        .collect();
+    sources.sort_by_key(Source::z_index);
+
+    let (width, height) = (image.width(), image.height());
+    let prepare_layer = prepare_layers(
+        width,
+        height,
+        variables,
+        &svg_options,
+        resampling,
+        &masks,
+        limits,
+    );
+    // prepared in parallel in chunks of one source per thread and drawn right after, so only
+    // a chunk of the layers is held at a time
+    let prepare = |source: &Source| match source {
+        Source::Image(layer, metadata) => prepare_layer((layer, metadata)),
+        Source::Text(text) => text.render(width, height, &svg_options, resampling, &masks, limits),
+    };
+    let mut stack = LayerStack::new(image);
+    for chunk in sources.chunks(rayon::current_num_threads()) {
+        let placed = chunk
+            .par_iter()
+            .map(prepare)
+            .collect::<Result<Vec<_>, _>>()?;
+        for layer in placed {
+            stack.push(layer);
+        }
+    }
+    let result = stack.finish();
+    limits.check_output(result.width(), result.height())?;
+
+    let output_metadata = match &payload.metadata {
+        Some(Json(options)) => options.output(source_metadata)?,
+        None => MetadataOptions::default().output(source_metadata)?,
+    };
+    encode(&result, format, payload.quality, &output_metadata)
+}
@@ -0,0 +1,103 @@
+use std::{io::Cursor, sync::Arc};
+
+use imageproc::image::{self, ImageError, ImageReader};
+use resvg::usvg::{ImageHrefResolver, Options};
+
+use crate::AppError;
+
+/// Most bytes a decoder may need per pixel, for 32 bit float rgba.
+const MAX_BYTES_PER_PIXEL: u64 = 16;
+
+/// Largest images the server decodes or produces, so a small file declaring a huge image
+/// cannot exhaust the memory.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Largest width times height.
+    pub max_pixels: u64,
+}
+
+impl Limits {
+    /// Limits for the decoders of the image crate, they reject the image before allocating it.
+    pub fn decoder(&self) -> image::Limits {
+        let mut limits = image::Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_pixels.saturating_mul(MAX_BYTES_PER_PIXEL));
+        limits
+    }
+
+    /// Resolves the `<image>` elements of svgs, the decoders of resvg have no limits so raster
+    /// images larger than the limits are dropped before they reach them. Hrefs to files are
+    /// dropped as well, clients must not read the files of the server.
+    pub fn image_href_resolver(self) -> ImageHrefResolver<'static> {
+        let resolve_data = ImageHrefResolver::default_data_resolver();
+        ImageHrefResolver {
+            resolve_data: Box::new(move |mime: &str, data: Arc<Vec<u8>>, options: &Options| {
+                let reader = ImageReader::new(Cursor::new(data.as_slice()))
+                    .with_guessed_format()
+                    .ok()?;
+                // nested svgs are parsed with these options and resolve their images here too
+                if reader.format().is_some() {
+                    let (width, height) = reader.into_dimensions().ok()?;
+                    if self.exceeded_by(width, height) {
+                        return None;
+                    }
+                }
+                resolve_data(mime, data, options)
+            }),
+            resolve_string: Box::new(|_, _| None),
+        }
+    }
+
+    /// Turns the errors of decoders running into the limits into `ImageTooLarge`.
+    pub fn decoding_error(&self, err: ImageError) -> AppError {
+        match err {
+            ImageError::Limits(_) => AppError::ImageTooLarge(format!(
+                "The image is larger than {} or needs too much memory to decode",
+                self.describe()
+            )),
+            err => AppError::DecodingFailure(err),
+        }
+    }
+
+    /// Checks the size of a decoded image.
+    pub fn check_decoded(&self, width: u32, height: u32) -> Result<(), AppError> {
+        if self.exceeded_by(width, height) {
+            return Err(AppError::ImageTooLarge(format!(
+                "The image has {}x{} pixels, the limit is {}",
+                width,
+                height,
+                self.describe()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks the size of an image before it is rendered or resized.
+    pub fn check_output(&self, width: u32, height: u32) -> Result<(), AppError> {
+        if self.exceeded_by(width, height) {
+            return Err(AppError::OutputTooLarge(format!(
+                "An image of {}x{} pixels would be produced, the limit is {}",
+                width,
+                height,
+                self.describe()
+            )));
+        }
+        Ok(())
+    }
+
+    fn exceeded_by(&self, width: u32, height: u32) -> bool {
+        width > self.max_width
+            || height > self.max_height
+            || width as u64 * height as u64 > self.max_pixels
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{}x{} and {} pixels in total",
+            self.max_width, self.max_height, self.max_pixels
+        )
+    }
+}
@@ -0,0 +1,86 @@
+use axum::body::Bytes;
+use axum_typed_multipart::FieldData;
+use imageproc::{
+    image::{DynamicImage, GrayImage, Luma, Rgba},
+    map::{map_colors, map_colors2},
+};
+use resvg::tiny_skia::IntSize;
+use serde::Deserialize;
+
+use crate::{
+    fonts::SvgOptions,
+    layer::{decode_image, is_svg, Resampling},
+    limits::Limits,
+    svg::{parse_svg, render_svg, ViewBox},
+    AppError,
+};
+
+/// Controls where a layer shows through, the mask is stretched over the whole layer.
+#[derive(Deserialize)]
+pub struct Mask {
+    /// File name of a `masks` part, in a pipeline the name of a part.
+    pub source: String,
+    #[serde(default)]
+    pub mode: MaskMode,
+    /// Shows the layer where the mask is dark or transparent instead.
+    #[serde(default)]
+    pub invert: bool,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum MaskMode {
+    /// White shows the layer and black hides it, transparent parts hide it as well.
+    #[default]
+    Luminance,
+    /// Only the alpha channel of the mask counts.
+    Alpha,
+}
+
+impl Mask {
+    /// Renders the mask at the size of the layer.
+    pub fn render(
+        &self,
+        part: &FieldData<Bytes>,
+        size: IntSize,
+        svg_options: &SvgOptions,
+        resampling: Resampling,
+        limits: Limits,
+    ) -> Result<GrayImage, AppError> {
+        let image = if is_svg(part) {
+            let tree = parse_svg(&part.contents, svg_options)?;
+            let transform = ViewBox::read(&part.contents, tree.size())
+                .transform_to(tree.size(), size.to_size());
+            render_svg(&tree, size, transform)?
+        } else {
+            let image = decode_image(part, limits)?;
+            if image.width() == size.width() && image.height() == size.height() {
+                image
+            } else {
+                image.resize_exact(size.width(), size.height(), resampling.into())
+            }
+        };
+
+        Ok(map_colors(&image.to_rgba8(), |Rgba([r, g, b, a])| {
+            let value = match self.mode {
+                MaskMode::Luminance => {
+                    let luminance = 0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32;
+                    (luminance * a as f32 / 255.0).round() as u8
+                }
+                MaskMode::Alpha => a,
+            };
+            Luma([if self.invert { 255 - value } else { value }])
+        }))
+    }
+}
+
+/// Multiplies the alpha of the image with the mask, both have the same size.
+pub fn apply_mask(image: &DynamicImage, mask: &GrayImage) -> DynamicImage {
+    DynamicImage::ImageRgba8(map_colors2(
+        &image.to_rgba8(),
+        mask,
+        |Rgba([r, g, b, a]), Luma([coverage])| {
+            Rgba([r, g, b, (a as u16 * coverage as u16 / 255) as u8])
+        },
+    ))
+}
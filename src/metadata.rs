@@ -0,0 +1,129 @@
+use std::io::Cursor;
+
+use exif::{experimental::Writer, Field, In, Reader, Tag, Value};
+use serde::Deserialize;
+
+use crate::AppError;
+
+/// Which metadata ends up in the output, sent as the json `metadata` field.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct MetadataOptions {
+    /// Drop all metadata of the source image, including the color profile.
+    pub strip: bool,
+    /// Copy the ICC color profile of the source image, defaults to true.
+    pub icc_profile: Option<bool>,
+    /// EXIF fields copied from the source image.
+    pub exif: Vec<ExifField>,
+    /// Written into the output, replacing the value of the source image.
+    pub copyright: Option<String>,
+    pub artist: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExifField {
+    Copyright,
+    Artist,
+    /// When the photo was taken, digitized and last changed.
+    Date,
+}
+
+impl ExifField {
+    fn tags(self) -> &'static [Tag] {
+        match self {
+            ExifField::Copyright => &[Tag::Copyright],
+            ExifField::Artist => &[Tag::Artist],
+            ExifField::Date => &[
+                Tag::DateTime,
+                Tag::DateTimeOriginal,
+                Tag::DateTimeDigitized,
+                Tag::OffsetTime,
+                Tag::OffsetTimeOriginal,
+                Tag::OffsetTimeDigitized,
+            ],
+        }
+    }
+}
+
+/// Metadata read from the base image.
+#[derive(Default)]
+pub struct SourceMetadata {
+    pub icc_profile: Option<Vec<u8>>,
+    /// The TIFF structure of the EXIF block, without the `Exif` header.
+    pub exif: Option<Vec<u8>>,
+}
+
+/// Metadata handed to the encoder, formats that cannot hold a kind of metadata drop it.
+#[derive(Default)]
+pub struct OutputMetadata {
+    pub icc_profile: Option<Vec<u8>>,
+    pub exif: Option<Vec<u8>>,
+}
+
+impl MetadataOptions {
+    pub fn output(&self, source: SourceMetadata) -> Result<OutputMetadata, AppError> {
+        let (icc_profile, exif) = if self.strip {
+            (None, None)
+        } else {
+            (
+                source
+                    .icc_profile
+                    .filter(|profile| self.icc_profile.unwrap_or(true) && is_rgb_profile(profile)),
+                source.exif,
+            )
+        };
+
+        // a broken exif block only loses the copied fields
+        let source_exif = exif.and_then(|exif| Reader::new().read_raw(exif).ok());
+        let mut fields: Vec<Field> = source_exif
+            .iter()
+            .flat_map(|exif| exif.fields())
+            .filter(|field| {
+                field.ifd_num == In::PRIMARY
+                    && self
+                        .exif
+                        .iter()
+                        .any(|selected| selected.tags().contains(&field.tag))
+            })
+            .cloned()
+            .collect();
+        for (tag, value) in [
+            (Tag::Copyright, &self.copyright),
+            (Tag::Artist, &self.artist),
+        ] {
+            if let Some(value) = value {
+                fields.retain(|field| field.tag != tag);
+                fields.push(Field {
+                    tag,
+                    ifd_num: In::PRIMARY,
+                    value: Value::Ascii(vec![value.clone().into_bytes()]),
+                });
+            }
+        }
+
+        let exif = if fields.is_empty() {
+            None
+        } else {
+            Some(write_exif(&fields)?)
+        };
+        Ok(OutputMetadata { icc_profile, exif })
+    }
+}
+
+/// The output is always written as rgb, a gray or cmyk profile would misinterpret it.
+fn is_rgb_profile(profile: &[u8]) -> bool {
+    profile.get(16..20) == Some(b"RGB ")
+}
+
+fn write_exif(fields: &[Field]) -> Result<Vec<u8>, AppError> {
+    let mut writer = Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let mut output = Cursor::new(vec![]);
+    writer
+        .write(&mut output, false)
+        .map_err(|_| AppError::EncodingFailure)?;
+    Ok(output.into_inner())
+}
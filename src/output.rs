@@ -0,0 +1,227 @@
+use std::io::Cursor;
+
+use axum::{
+    http::{header, HeaderMap},
+    response::IntoResponse,
+};
+use axum_typed_multipart::TryFromField;
+use clap::ValueEnum;
+use imageproc::image::{
+    codecs::{
+        avif::AvifEncoder, bmp::BmpEncoder, gif::GifEncoder, jpeg::JpegEncoder, png::PngEncoder,
+        tiff::TiffEncoder, webp::WebPEncoder,
+    },
+    DynamicImage, Frame, ImageEncoder, ImageResult,
+};
+
+use serde::Deserialize;
+
+use crate::{metadata::OutputMetadata, AppError};
+
+const DEFAULT_JPEG_QUALITY: u8 = 75;
+const DEFAULT_AVIF_QUALITY: u8 = 80;
+const AVIF_SPEED: u8 = 8;
+
+#[derive(TryFromField, Deserialize, ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+#[try_from_field(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Webp,
+    Gif,
+    Bmp,
+    Tiff,
+    Avif,
+}
+
+impl OutputFormat {
+    const ALL: [OutputFormat; 7] = [
+        OutputFormat::Png,
+        OutputFormat::Jpeg,
+        OutputFormat::Webp,
+        OutputFormat::Gif,
+        OutputFormat::Bmp,
+        OutputFormat::Tiff,
+        OutputFormat::Avif,
+    ];
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Webp => "image/webp",
+            OutputFormat::Gif => "image/gif",
+            OutputFormat::Bmp => "image/bmp",
+            OutputFormat::Tiff => "image/tiff",
+            OutputFormat::Avif => "image/avif",
+        }
+    }
+
+    fn from_mime_type(mime_type: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|format| format.mime_type().eq_ignore_ascii_case(mime_type))
+    }
+
+    /// Picks the format with the highest q-value from the `Accept` header, on a tie the more
+    /// specific range wins.
+    ///
+    /// Wildcards, a missing header and headers without any format we can produce resolve to
+    /// `default`.
+    pub fn negotiate(headers: &HeaderMap, default: OutputFormat) -> OutputFormat {
+        let Some(accept) = headers
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return default;
+        };
+
+        // (q-value, specificity, format), exact types are more specific than image/* and */*
+        let mut best: Option<(f32, u8, OutputFormat)> = None;
+        for range in accept.split(',') {
+            let mut params = range.split(';').map(str::trim);
+            let mime_type = params.next().unwrap_or_default();
+            let quality = params
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let candidate = match mime_type {
+                "*/*" => Some((0, default)),
+                "image/*" => Some((1, default)),
+                mime_type => Self::from_mime_type(mime_type).map(|format| (2, format)),
+            };
+            if let Some((specificity, format)) = candidate {
+                let better = best.is_none_or(|(best_quality, best_specificity, _)| {
+                    quality > best_quality
+                        || (quality == best_quality && specificity > best_specificity)
+                });
+                if quality > 0.0 && better {
+                    best = Some((quality, specificity, format));
+                }
+            }
+        }
+        best.map_or(default, |(_, _, format)| format)
+    }
+}
+
+/// Encodes the image, `quality` (1-100) is only used by the lossy jpeg and avif encoders.
+pub fn encode(
+    image: &DynamicImage,
+    format: OutputFormat,
+    quality: Option<u8>,
+    metadata: &OutputMetadata,
+) -> Result<Vec<u8>, AppError> {
+    let mut output = Cursor::new(vec![]);
+    write(image, format, quality, metadata, &mut output).map_err(|_| AppError::EncodingFailure)?;
+    Ok(output.into_inner())
+}
+
+/// The encoded image with its content type, the format can depend on the `Accept` header.
+pub fn image_response(encoded: Vec<u8>, format: OutputFormat) -> impl IntoResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, format.mime_type().parse().unwrap());
+    headers.insert(header::VARY, "Accept".parse().unwrap());
+    (headers, encoded)
+}
+
+fn write(
+    image: &DynamicImage,
+    format: OutputFormat,
+    quality: Option<u8>,
+    metadata: &OutputMetadata,
+    output: &mut Cursor<Vec<u8>>,
+) -> ImageResult<()> {
+    match format {
+        OutputFormat::Png => image
+            .to_rgba8()
+            .write_with_encoder(with_metadata(PngEncoder::new(output), metadata)),
+        // jpeg has no alpha channel
+        OutputFormat::Jpeg => image.to_rgb8().write_with_encoder(with_metadata(
+            JpegEncoder::new_with_quality(
+                output,
+                quality.unwrap_or(DEFAULT_JPEG_QUALITY).clamp(1, 100),
+            ),
+            metadata,
+        )),
+        OutputFormat::Webp => image
+            .to_rgba8()
+            .write_with_encoder(with_metadata(WebPEncoder::new_lossless(output), metadata)),
+        OutputFormat::Gif => GifEncoder::new(output).encode_frame(Frame::new(image.to_rgba8())),
+        OutputFormat::Bmp => image.to_rgba8().write_with_encoder(BmpEncoder::new(output)),
+        OutputFormat::Tiff => image
+            .to_rgba8()
+            .write_with_encoder(with_metadata(TiffEncoder::new(output), metadata)),
+        OutputFormat::Avif => image.to_rgba8().write_with_encoder(with_metadata(
+            AvifEncoder::new_with_speed_quality(
+                output,
+                AVIF_SPEED,
+                quality.unwrap_or(DEFAULT_AVIF_QUALITY).clamp(1, 100),
+            ),
+            metadata,
+        )),
+    }
+}
+
+/// Hands the metadata to the encoder, kinds of metadata the format has no place for are dropped.
+fn with_metadata<E: ImageEncoder>(mut encoder: E, metadata: &OutputMetadata) -> E {
+    if let Some(icc_profile) = &metadata.icc_profile {
+        let _ = encoder.set_icc_profile(icc_profile.clone());
+    }
+    if let Some(exif) = &metadata.exif {
+        let _ = encoder.set_exif_metadata(exif.clone());
+    }
+    encoder
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn negotiate(accept: &str) -> OutputFormat {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_str(accept).unwrap());
+        OutputFormat::negotiate(&headers, OutputFormat::Jpeg)
+    }
+
+    #[test]
+    fn missing_header_uses_default() {
+        assert_eq!(
+            OutputFormat::negotiate(&HeaderMap::new(), OutputFormat::Png),
+            OutputFormat::Png
+        );
+    }
+
+    #[test]
+    fn picks_highest_quality() {
+        assert_eq!(
+            negotiate("image/png;q=0.5, image/webp;q=0.9"),
+            OutputFormat::Webp
+        );
+        assert_eq!(negotiate("image/avif, image/png;q=0.8"), OutputFormat::Avif);
+    }
+
+    #[test]
+    fn wildcards_use_default() {
+        assert_eq!(negotiate("*/*"), OutputFormat::Jpeg);
+        assert_eq!(negotiate("image/*"), OutputFormat::Jpeg);
+    }
+
+    #[test]
+    fn specific_type_wins_tie() {
+        assert_eq!(negotiate("*/*, image/webp"), OutputFormat::Webp);
+        assert_eq!(
+            negotiate("image/*;q=0.8, image/png;q=0.8"),
+            OutputFormat::Png
+        );
+        assert_eq!(negotiate("image/png;q=0.5, */*"), OutputFormat::Jpeg);
+    }
+
+    #[test]
+    fn unknown_types_use_default() {
+        assert_eq!(negotiate("application/json"), OutputFormat::Jpeg);
+        assert_eq!(negotiate("image/png;q=0"), OutputFormat::Jpeg);
+    }
+}
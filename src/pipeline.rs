@@ -0,0 +1,301 @@
+use std::{collections::HashMap, iter, slice};
+
+use axum::{
+    body::Bytes,
+    extract::{multipart::MultipartError, Multipart, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use axum_typed_multipart::{FieldData, FieldMetadata};
+use serde::{de, Deserialize, Deserializer};
+use serde_json::Value;
+
+use crate::{
+    canvas::Canvas,
+    check_steps,
+    filter::{apply_filters, Filter},
+    layer::{decode_base_image, prepare_layers, LayerMetadata, LayerStack, Resampling},
+    metadata::{MetadataOptions, SourceMetadata},
+    output::{encode, image_response, OutputFormat},
+    text::TextLayer,
+    transform::{apply_transforms, Operation},
+    AppError, AppState,
+};
+
+/// The only version of the pipeline format so far.
+const PIPELINE_VERSION: u32 = 1;
+/// Multipart field with the pipeline, every other field is a part the steps refer to by name.
+const PIPELINE_FIELD: &str = "pipeline";
+
+/// A composition described as an ordered list of steps.
+#[derive(Deserialize)]
+struct Pipeline {
+    /// Format version, pipelines written for a newer version are rejected instead of misread.
+    version: u32,
+    steps: Vec<Step>,
+    /// Values for the placeholders in svg overlays.
+    #[serde(default)]
+    variables: HashMap<String, String>,
+    /// Names of the parts with fonts only available to this pipeline.
+    #[serde(default)]
+    fonts: Vec<String>,
+    strict_fonts: Option<bool>,
+    /// Filter for resizing, defaults to catmull-rom.
+    #[serde(default)]
+    resampling: Resampling,
+}
+
+#[derive(Deserialize)]
+#[serde(remote = "Self", tag = "op", rename_all = "kebab-case")]
+enum Step {
+    /// Decodes the part `source` into the image the following steps work on.
+    Decode {
+        source: String,
+        auto_orient: Option<bool>,
+    },
+    /// Starts with an empty image instead of decoding one.
+    Canvas {
+        #[serde(flatten)]
+        canvas: Canvas,
+    },
+    /// Draws the part `source` onto the image, placed like a layer of the multipart api.
+    Overlay {
+        source: String,
+        #[serde(flatten)]
+        metadata: LayerMetadata,
+    },
+    /// Draws a caption onto the image.
+    Text {
+        #[serde(flatten)]
+        text: TextLayer,
+    },
+    /// Adjusts the colors or sharpness of the image.
+    Filter {
+        #[serde(flatten)]
+        filter: Filter,
+    },
+    /// Writes the image, has to be the last step. Without it the format is negotiated.
+    Encode {
+        format: Option<OutputFormat>,
+        quality: Option<u8>,
+        #[serde(default)]
+        metadata: MetadataOptions,
+    },
+    /// Resize, crop, rotate and flip, the operations of the `transforms` field.
+    #[serde(skip)]
+    Transform(Operation),
+}
+
+impl<'de> Deserialize<'de> for Step {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // with an untagged variant for the transforms every mistake would be reported as
+        // "data did not match any variant", so the tag decides which type reads the step
+        let value = Value::deserialize(deserializer)?;
+        match value.get("op").and_then(Value::as_str) {
+            Some("decode" | "canvas" | "overlay" | "text" | "filter" | "encode") => {
+                Step::deserialize(value)
+            }
+            _ => Operation::deserialize(value).map(Step::Transform),
+        }
+        .map_err(de::Error::custom)
+    }
+}
+
+/// Runs the pipeline in the `pipeline` field on the other parts of the request.
+pub async fn run_pipeline(
+    State(state): State<AppState>,
+    request_headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, AppError> {
+    let mut pipeline = None;
+    let mut parts = HashMap::new();
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| multipart_error(err, &state))?
+    {
+        let metadata = FieldMetadata {
+            name: field.name().map(String::from),
+            file_name: field.file_name().map(String::from),
+            content_type: field.content_type().map(String::from),
+            headers: field.headers().clone(),
+        };
+        let contents = field
+            .bytes()
+            .await
+            .map_err(|err| multipart_error(err, &state))?;
+        let Some(name) = metadata.name.clone() else {
+            continue;
+        };
+        if name == PIPELINE_FIELD {
+            pipeline = Some(
+                serde_json::from_slice::<Pipeline>(&contents)
+                    .map_err(|err| AppError::InvalidPipeline(err.to_string()))?,
+            );
+        } else if parts
+            .insert(
+                name.clone(),
+                state.templates.resolve(&FieldData { metadata, contents })?,
+            )
+            .is_some()
+        {
+            return Err(AppError::InvalidPipeline(format!(
+                "there is more than one part named {}",
+                name
+            )));
+        }
+    }
+
+    let pipeline = pipeline.ok_or(AppError::InvalidPipeline(format!(
+        "missing the {} field",
+        PIPELINE_FIELD
+    )))?;
+    if pipeline.version != PIPELINE_VERSION {
+        return Err(AppError::InvalidPipeline(format!(
+            "unsupported version {}, this server understands version {}",
+            pipeline.version, PIPELINE_VERSION
+        )));
+    }
+    let layer_count = pipeline
+        .steps
+        .iter()
+        .filter(|step| matches!(step, Step::Overlay { .. } | Step::Text { .. }))
+        .count();
+    if layer_count > state.config.max_layers {
+        return Err(AppError::TooManyLayers(state.config.max_layers));
+    }
+    let step_counts = pipeline.steps.iter().filter_map(|step| match step {
+        Step::Overlay { metadata, .. } => Some(metadata.filters.len()),
+        Step::Text { text } => Some(text.placement.filters.len()),
+        _ => None,
+    });
+    check_steps(
+        iter::once(pipeline.steps.len()).chain(step_counts),
+        &state.config,
+    )?;
+    // only an error when the pipeline has no encode step with a format
+    let negotiated = OutputFormat::negotiate(&request_headers, state.config.default_format);
+    let jobs = state.jobs.clone();
+    let (encoded, format) = jobs
+        .run(move || execute(&state, pipeline, parts, negotiated))
+        .await?;
+    Ok(image_response(encoded, format))
+}
+
+/// Runs the steps and encodes the result, runs on the blocking pool.
+fn execute(
+    state: &AppState,
+    pipeline: Pipeline,
+    mut parts: HashMap<String, FieldData<Bytes>>,
+    negotiated: OutputFormat,
+) -> Result<(Vec<u8>, OutputFormat), AppError> {
+    let limits = state.config.limits();
+    let fonts = pipeline
+        .fonts
+        .iter()
+        .map(|name| {
+            parts
+                .remove(name)
+                .ok_or(AppError::MissingPart(name.clone()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let svg_options = state.fonts.svg_options(&fonts, pipeline.strict_fonts)?;
+    let part = |name: &String| parts.get(name).ok_or(AppError::MissingPart(name.clone()));
+
+    let mut image = None;
+    let mut source_metadata = SourceMetadata::default();
+    let mut output = None;
+    for (index, step) in pipeline.steps.iter().enumerate() {
+        if output.is_some() {
+            return Err(AppError::InvalidPipeline(
+                "encode has to be the last step".into(),
+            ));
+        }
+        // steps after decode or canvas work on that image
+        let mut decoded = || {
+            image.take().ok_or(AppError::InvalidPipeline(format!(
+                "step {} comes before the image is decoded or a canvas created",
+                index + 1
+            )))
+        };
+        match step {
+            Step::Decode {
+                source,
+                auto_orient,
+            } => {
+                let (decoded_image, metadata) =
+                    decode_base_image(part(source)?, auto_orient.unwrap_or(true), limits)?;
+                image = Some(decoded_image);
+                source_metadata = metadata;
+            }
+            Step::Canvas { canvas } => {
+                image = Some(canvas.render(&svg_options, limits)?);
+                source_metadata = SourceMetadata::default();
+            }
+            Step::Transform(operation) => {
+                image = Some(apply_transforms(
+                    decoded()?,
+                    slice::from_ref(operation),
+                    pipeline.resampling,
+                    limits,
+                )?);
+            }
+            Step::Overlay { source, metadata } => {
+                let current = decoded()?;
+                let layer = prepare_layers(
+                    current.width(),
+                    current.height(),
+                    &pipeline.variables,
+                    &svg_options,
+                    pipeline.resampling,
+                    &parts,
+                    limits,
+                )((part(source)?, metadata))?;
+                let mut stack = LayerStack::new(current);
+                stack.push(layer);
+                image = Some(stack.finish());
+            }
+            Step::Filter { filter } => {
+                image = Some(apply_filters(decoded()?, slice::from_ref(filter))?);
+            }
+            Step::Text { text } => {
+                let current = decoded()?;
+                let layer = text.render(
+                    current.width(),
+                    current.height(),
+                    &svg_options,
+                    pipeline.resampling,
+                    &parts,
+                    limits,
+                )?;
+                let mut stack = LayerStack::new(current);
+                stack.push(layer);
+                image = Some(stack.finish());
+            }
+            Step::Encode {
+                format,
+                quality,
+                metadata,
+            } => output = Some((*format, *quality, metadata)),
+        }
+    }
+
+    let image = image.ok_or(AppError::InvalidPipeline(
+        "the pipeline never decodes an image or creates a canvas".into(),
+    ))?;
+    let default_metadata = MetadataOptions::default();
+    let (format, quality, metadata_options) = output.unwrap_or((None, None, &default_metadata));
+    let format = format.unwrap_or(negotiated);
+    limits.check_output(image.width(), image.height())?;
+    let output_metadata = metadata_options.output(source_metadata)?;
+    let encoded = encode(&image, format, quality, &output_metadata)?;
+    Ok((encoded, format))
+}
+
+fn multipart_error(err: MultipartError, state: &AppState) -> AppError {
+    if err.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        AppError::PayloadTooLarge(state.config.max_upload_size)
+    } else {
+        AppError::InvalidPipeline(err.body_text())
+    }
+}
@@ -0,0 +1,486 @@
+use std::{borrow::Cow, collections::HashMap, ops::Range};
+
+use imageproc::image::{DynamicImage, Rgba, RgbaImage};
+use resvg::{
+    tiny_skia::{self, IntSize, NonZeroRect, Size, Transform},
+    usvg::{self, roxmltree},
+};
+
+use crate::{fonts::SvgOptions, AppError};
+
+/// Largest svg after its variables are filled in, placeholders repeated many times could
+/// otherwise grow a small template without bound.
+const MAX_FILLED_SIZE: usize = 16 * 1024 * 1024;
+
+pub fn parse_svg(data: &[u8], svg_options: &SvgOptions) -> Result<usvg::Tree, AppError> {
+    let tree = usvg::Tree::from_data(data, &svg_options.options)
+        .map_err(|_| AppError::SvgParserFailure)?;
+    svg_options.check_missing_fonts()?;
+    Ok(tree)
+}
+
+/// Renders the tree into a pixmap of `size`, `transfrom` maps the svg size onto the pixmap.
+pub fn render_svg(
+    tree: &usvg::Tree,
+    size: IntSize,
+    transfrom: Transform,
+) -> Result<DynamicImage, AppError> {
+    let mut pixmap =
+        tiny_skia::Pixmap::new(size.width(), size.height()).ok_or(AppError::InvalidSize)?;
+
+    resvg::render(tree, transfrom, &mut pixmap.as_mut());
+
+    Ok(DynamicImage::ImageRgba8(pixmap_to_image(&pixmap)))
+}
+
+/// Converts the premultiplied pixmap into straight alpha, which is what `image` expects.
+pub fn pixmap_to_image(pixmap: &tiny_skia::Pixmap) -> RgbaImage {
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    RgbaImage::from_raw(pixmap.width(), pixmap.height(), pixels)
+        .expect("pixmap has four bytes per pixel")
+}
+
+/// Converts the image into a premultiplied pixmap for drawing with tiny-skia.
+pub fn image_to_pixmap(image: &RgbaImage) -> Result<tiny_skia::Pixmap, AppError> {
+    let size = IntSize::from_wh(image.width(), image.height()).ok_or(AppError::InvalidSize)?;
+    let pixels = image
+        .pixels()
+        .flat_map(|&Rgba([r, g, b, a])| {
+            let color = tiny_skia::ColorU8::from_rgba(r, g, b, a).premultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    tiny_skia::Pixmap::from_vec(pixels, size).ok_or(AppError::InvalidSize)
+}
+
+/// The `viewBox` and `preserveAspectRatio` of the root element.
+///
+/// usvg already applies them for the intrinsic size of the svg, this is needed to apply them
+/// again for a different size.
+pub struct ViewBox {
+    rect: NonZeroRect,
+    /// Alignment of the view box, `None` stretches it.
+    align: Option<(f32, f32)>,
+    slice: bool,
+}
+
+impl ViewBox {
+    /// Reads the view box from the svg source, `size` is the size usvg determined for it.
+    pub fn read(data: &[u8], size: Size) -> ViewBox {
+        let options = roxmltree::ParsingOptions {
+            allow_dtd: true,
+            ..Default::default()
+        };
+        let document = std::str::from_utf8(data)
+            .ok()
+            .and_then(|text| roxmltree::Document::parse_with_options(text, options).ok());
+        let root = document.as_ref().map(|document| document.root_element());
+
+        let rect = root
+            .and_then(|root| root.attribute("viewBox"))
+            .and_then(|view_box| {
+                let numbers: Vec<f32> = view_box
+                    .split(|c: char| c.is_whitespace() || c == ',')
+                    .filter(|number| !number.is_empty())
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()
+                    .ok()?;
+                match numbers[..] {
+                    [x, y, width, height] => NonZeroRect::from_xywh(x, y, width, height),
+                    _ => None,
+                }
+            })
+            .or_else(|| NonZeroRect::from_xywh(0.0, 0.0, size.width(), size.height()))
+            .expect("usvg sizes are never empty");
+
+        let aspect = root
+            .and_then(|root| root.attribute("preserveAspectRatio"))
+            .unwrap_or_default();
+        let mut align = Some((0.5, 0.5));
+        let mut slice = false;
+        for part in aspect.split_whitespace() {
+            match part {
+                "none" => align = None,
+                "slice" => slice = true,
+                _ => {
+                    if let Some(factors) = align_factors(part) {
+                        align = Some(factors);
+                    }
+                }
+            }
+        }
+        ViewBox { rect, align, slice }
+    }
+
+    fn to_transform(&self, size: Size) -> Transform {
+        let rect = self.rect;
+        let sx = size.width() / rect.width();
+        let sy = size.height() / rect.height();
+        let Some((fx, fy)) = self.align else {
+            return Transform::from_row(sx, 0.0, 0.0, sy, -rect.x() * sx, -rect.y() * sy);
+        };
+        let scale = if self.slice { sx.max(sy) } else { sx.min(sy) };
+        let x = -rect.x() * scale + (size.width() - rect.width() * scale) * fx;
+        let y = -rect.y() * scale + (size.height() - rect.height() * scale) * fy;
+        Transform::from_row(scale, 0.0, 0.0, scale, x, y)
+    }
+
+    /// Transform that lays the tree out as if `target` had been its size.
+    pub fn transform_to(&self, tree_size: Size, target: Size) -> Transform {
+        let into_view_box = self.to_transform(tree_size).invert().unwrap_or_default();
+        self.to_transform(target).pre_concat(into_view_box)
+    }
+}
+
+fn align_factors(align: &str) -> Option<(f32, f32)> {
+    let factor = |value: &str| match value {
+        "Min" => Some(0.0),
+        "Mid" => Some(0.5),
+        "Max" => Some(1.0),
+        _ => None,
+    };
+    let rest = align.strip_prefix('x')?;
+    let (x, y) = rest.split_once('Y')?;
+    Some((factor(x)?, factor(y)?))
+}
+
+/// Fills the svg template with the request variables.
+///
+/// Every variable replaces the content of the element with a matching `id` and all
+/// `{{name}}` placeholders in the document. The document is filled in a single pass, values
+/// that contain placeholders are inserted as they are.
+pub fn substitute_variables<'a>(
+    data: &'a [u8],
+    variables: &HashMap<String, String>,
+) -> Result<Cow<'a, [u8]>, AppError> {
+    if variables.is_empty() {
+        return Ok(Cow::Borrowed(data));
+    }
+    let text = std::str::from_utf8(data).map_err(|_| AppError::SvgParserFailure)?;
+
+    let options = roxmltree::ParsingOptions {
+        allow_dtd: true,
+        ..Default::default()
+    };
+    let document = roxmltree::Document::parse_with_options(text, options)
+        .map_err(|_| AppError::SvgParserFailure)?;
+    let mut replacements: Vec<(Range<usize>, &str, Option<&str>)> = document
+        .descendants()
+        .filter_map(|node| {
+            let value = variables.get(node.attribute("id")?)?;
+            match (node.first_child(), node.last_child()) {
+                (Some(first), Some(last)) => {
+                    Some((first.range().start..last.range().end, value.as_str(), None))
+                }
+                _ => {
+                    let (range, closing) = empty_content(text, node)?;
+                    Some((range, value.as_str(), closing))
+                }
+            }
+        })
+        .collect();
+    // nested elements would overlap, the outermost one wins
+    replacements.sort_by_key(|(range, _, _)| range.start);
+    let mut filled = String::with_capacity(text.len());
+    let mut position = 0;
+    for (range, value, closing) in replacements {
+        if range.start < position {
+            continue;
+        }
+        fill_placeholders(&text[position..range.start], variables, &mut filled)?;
+        match closing {
+            Some(name) => {
+                filled.push('>');
+                filled.push_str(&escape(value));
+                filled.push_str(&format!("</{}>", name));
+            }
+            None => filled.push_str(&escape(value)),
+        }
+        check_filled_size(&filled)?;
+        position = range.end;
+    }
+    fill_placeholders(&text[position..], variables, &mut filled)?;
+    Ok(Cow::Owned(filled.into_bytes()))
+}
+
+/// Where the content of an element without children goes, right after its start tag.
+///
+/// A self-closing element gets the `/>` replaced, the name of its end tag is returned with it.
+fn empty_content<'a>(
+    text: &'a str,
+    node: roxmltree::Node,
+) -> Option<(Range<usize>, Option<&'a str>)> {
+    let element = node.range();
+    // a `>` in an attribute value is valid, so the search starts after the last attribute
+    let attributes_end = node
+        .attributes()
+        .map(|attribute| attribute.range().end)
+        .max()?;
+    let end = attributes_end + text[attributes_end..element.end].find('>')?;
+    if text[..end].ends_with('/') {
+        let tag = &text[element.start + 1..attributes_end];
+        let name = &tag[..tag.find(char::is_whitespace)?];
+        Some((end - 1..end + 1, Some(name)))
+    } else {
+        Some((end + 1..end + 1, None))
+    }
+}
+
+/// Copies `text` into `filled` with the `{{name}}` placeholders of known variables replaced.
+fn fill_placeholders(
+    text: &str,
+    variables: &HashMap<String, String>,
+    filled: &mut String,
+) -> Result<(), AppError> {
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        let (name, tail) = after.split_at(after.find(['{', '}']).unwrap_or(after.len()));
+        match (tail.strip_prefix("}}"), variables.get(name)) {
+            (Some(tail), Some(value)) => {
+                filled.push_str(&rest[..start]);
+                filled.push_str(&escape(value));
+                check_filled_size(filled)?;
+                rest = tail;
+            }
+            // not a placeholder, a placeholder may still start at the next brace
+            _ => {
+                filled.push_str(&rest[..start + 1]);
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    filled.push_str(rest);
+    check_filled_size(filled)
+}
+
+fn check_filled_size(filled: &str) -> Result<(), AppError> {
+    if filled.len() > MAX_FILLED_SIZE {
+        return Err(AppError::SvgTooLarge(MAX_FILLED_SIZE));
+    }
+    Ok(())
+}
+
+pub fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(width: f32, height: f32) -> Size {
+        Size::from_wh(width, height).unwrap()
+    }
+
+    fn read_view_box(attributes: &str, tree_size: Size) -> ViewBox {
+        let svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" {}/>"#,
+            attributes
+        );
+        ViewBox::read(svg.as_bytes(), tree_size)
+    }
+
+    #[test]
+    fn scales_to_target() {
+        let view_box = read_view_box(r#"viewBox="0 0 100 50""#, size(100.0, 50.0));
+        assert_eq!(
+            view_box.transform_to(size(100.0, 50.0), size(200.0, 100.0)),
+            Transform::from_scale(2.0, 2.0)
+        );
+    }
+
+    #[test]
+    fn centers_by_default() {
+        let view_box = read_view_box(r#"viewBox="0 0 100 50""#, size(100.0, 50.0));
+        assert_eq!(
+            view_box.transform_to(size(100.0, 50.0), size(200.0, 200.0)),
+            Transform::from_row(2.0, 0.0, 0.0, 2.0, 0.0, 50.0)
+        );
+    }
+
+    #[test]
+    fn none_stretches() {
+        let view_box = read_view_box(
+            r#"viewBox="0 0 100 50" preserveAspectRatio="none""#,
+            size(100.0, 50.0),
+        );
+        assert_eq!(
+            view_box.transform_to(size(100.0, 50.0), size(200.0, 200.0)),
+            Transform::from_scale(2.0, 4.0)
+        );
+    }
+
+    #[test]
+    fn slice_covers_target() {
+        let view_box = read_view_box(
+            r#"viewBox="0 0 100 50" preserveAspectRatio="xMidYMid slice""#,
+            size(100.0, 50.0),
+        );
+        assert_eq!(
+            view_box.transform_to(size(100.0, 50.0), size(200.0, 200.0)),
+            Transform::from_row(4.0, 0.0, 0.0, 4.0, -100.0, 0.0)
+        );
+        let view_box = read_view_box(
+            r#"viewBox="0 0 100 50" preserveAspectRatio="xMinYMin slice""#,
+            size(100.0, 50.0),
+        );
+        assert_eq!(
+            view_box.transform_to(size(100.0, 50.0), size(200.0, 200.0)),
+            Transform::from_scale(4.0, 4.0)
+        );
+    }
+
+    #[test]
+    fn tree_size_differs_from_view_box() {
+        // width and height of the svg are half of its view box
+        let view_box = read_view_box(
+            r#"width="50" height="25" viewBox="10 10 100 50""#,
+            size(50.0, 25.0),
+        );
+        assert_eq!(
+            view_box.transform_to(size(50.0, 25.0), size(100.0, 50.0)),
+            Transform::from_scale(2.0, 2.0)
+        );
+    }
+
+    #[test]
+    fn missing_view_box_uses_tree_size() {
+        let view_box = read_view_box(r#"width="40" height="20""#, size(40.0, 20.0));
+        assert_eq!(
+            view_box.transform_to(size(40.0, 20.0), size(80.0, 80.0)),
+            Transform::from_row(2.0, 0.0, 0.0, 2.0, 0.0, 20.0)
+        );
+    }
+
+    fn substitute(svg: &str, variables: &[(&str, &str)]) -> String {
+        let variables = variables
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let Ok(filled) = substitute_variables(svg.as_bytes(), &variables) else {
+            panic!("{} could not be filled", svg);
+        };
+        String::from_utf8(filled.into_owned()).unwrap()
+    }
+
+    #[test]
+    fn without_variables_borrows() {
+        let svg = b"<svg>{{name}}</svg>";
+        assert!(matches!(
+            substitute_variables(svg, &HashMap::new()),
+            Ok(Cow::Borrowed(data)) if data == svg
+        ));
+    }
+
+    #[test]
+    fn replaces_placeholders() {
+        assert_eq!(
+            substitute(
+                r#"<svg><text fill="{{color}}">Hi {{name}}, {{name}}</text></svg>"#,
+                &[("name", "Jane"), ("color", "red")]
+            ),
+            r#"<svg><text fill="red">Hi Jane, Jane</text></svg>"#
+        );
+    }
+
+    #[test]
+    fn replaces_element_content() {
+        assert_eq!(
+            substitute(
+                r#"<svg><text id="title">Old <tspan>title</tspan></text><text id="other">Kept</text></svg>"#,
+                &[("title", "New")]
+            ),
+            r#"<svg><text id="title">New</text><text id="other">Kept</text></svg>"#
+        );
+    }
+
+    #[test]
+    fn fills_empty_elements() {
+        assert_eq!(
+            substitute(
+                r#"<svg xmlns:svg="http://www.w3.org/2000/svg"><text id="a"></text><svg:text id="b" x="1>2"/><text id="c" /></svg>"#,
+                &[("a", "A"), ("b", "B"), ("c", "C")]
+            ),
+            r#"<svg xmlns:svg="http://www.w3.org/2000/svg"><text id="a">A</text><svg:text id="b" x="1>2">B</svg:text><text id="c" >C</text></svg>"#
+        );
+    }
+
+    #[test]
+    fn outermost_element_wins() {
+        assert_eq!(
+            substitute(
+                r#"<svg><text id="outer"><tspan id="inner">a</tspan></text></svg>"#,
+                &[("outer", "b"), ("inner", "c")]
+            ),
+            r#"<svg><text id="outer">b</text></svg>"#
+        );
+    }
+
+    #[test]
+    fn escapes_values() {
+        assert_eq!(
+            substitute(
+                r#"<svg><text id="title">x</text>{{name}}</svg>"#,
+                &[("title", "<b>"), ("name", "\"a\" & 'b'")]
+            ),
+            r#"<svg><text id="title">&lt;b&gt;</text>&quot;a&quot; &amp; &apos;b&apos;</svg>"#
+        );
+    }
+
+    #[test]
+    fn values_are_not_filled_again() {
+        let variables = [("a", "{{b}}"), ("b", "{{a}}"), ("title", "{{a}}")];
+        for _ in 0..10 {
+            assert_eq!(
+                substitute(
+                    r#"<svg><text id="title">x</text>{{a}} {{b}}</svg>"#,
+                    &variables
+                ),
+                r#"<svg><text id="title">{{a}}</text>{{b}} {{a}}</svg>"#
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_unknown_placeholders() {
+        assert_eq!(
+            substitute(
+                r#"<svg>{{{name}}} {{other}} {{ }} {{name</svg>"#,
+                &[("name", "Jane")]
+            ),
+            r#"<svg>{Jane} {{other}} {{ }} {{name</svg>"#
+        );
+    }
+
+    #[test]
+    fn rejects_large_results() {
+        let svg = format!("<svg>{}</svg>", "{{name}}".repeat(20_000));
+        let variables = HashMap::from([("name".to_string(), "x".repeat(1024))]);
+        assert!(matches!(
+            substitute_variables(svg.as_bytes(), &variables),
+            Err(AppError::SvgTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_svg() {
+        let variables = HashMap::from([("name".to_string(), "value".to_string())]);
+        assert!(matches!(
+            substitute_variables(b"<svg>", &variables),
+            Err(AppError::SvgParserFailure)
+        ));
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use axum::{
+    body::Bytes,
+    extract::{rejection::BytesRejection, Path as UrlPath, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use axum_typed_multipart::{FieldData, FieldMetadata};
+use imageproc::image::ImageFormat;
+use serde::Serialize;
+
+use crate::{layer::decode_image, svg::parse_svg, AppError, AppState};
+
+const SVG_MIME_TYPE: &str = "image/svg+xml";
+/// Layers whose contents start with this prefix reference a stored template.
+const TEMPLATE_PREFIX: &[u8] = b"template:";
+
+struct Template {
+    content_type: String,
+    contents: Bytes,
+}
+
+/// Named overlays kept in memory and mirrored to a directory on disk.
+pub struct TemplateStore {
+    directory: PathBuf,
+    templates: RwLock<HashMap<String, Template>>,
+}
+
+#[derive(Serialize)]
+pub struct TemplateInfo {
+    name: String,
+    content_type: String,
+}
+
+fn mime_type_from_extension(extension: &str) -> Option<String> {
+    if extension.eq_ignore_ascii_case("svg") {
+        return Some(SVG_MIME_TYPE.into());
+    }
+    ImageFormat::from_extension(extension).map(|format| format.to_mime_type().into())
+}
+
+fn extension_from_mime_type(mime_type: &str) -> Option<&'static str> {
+    if mime_type == SVG_MIME_TYPE {
+        return Some("svg");
+    }
+    ImageFormat::from_mime_type(mime_type)?
+        .extensions_str()
+        .first()
+        .copied()
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl TemplateStore {
+    /// Loads every image and svg in `directory`, the file stem becomes the template name.
+    pub fn load(directory: impl AsRef<Path>) -> io::Result<TemplateStore> {
+        let directory = directory.as_ref().to_path_buf();
+        let mut templates = HashMap::new();
+        if directory.is_dir() {
+            for entry in fs::read_dir(&directory)? {
+                let path = entry?.path();
+                let (Some(name), Some(content_type)) = (
+                    path.file_stem().and_then(|stem| stem.to_str()),
+                    path.extension()
+                        .and_then(|extension| extension.to_str())
+                        .and_then(mime_type_from_extension),
+                ) else {
+                    continue;
+                };
+                if !is_valid_name(name) {
+                    continue;
+                }
+                let contents = Bytes::from(fs::read(&path)?);
+                templates.insert(
+                    name.to_string(),
+                    Template {
+                        content_type,
+                        contents,
+                    },
+                );
+            }
+        }
+        Ok(TemplateStore {
+            directory,
+            templates: RwLock::new(templates),
+        })
+    }
+
+    /// Replaces a `template:<name>` reference with the stored template, other layers are returned as is.
+    pub fn resolve(&self, layer: &FieldData<Bytes>) -> Result<FieldData<Bytes>, AppError> {
+        let reference = match layer.contents.strip_prefix(TEMPLATE_PREFIX) {
+            Some(name) if layer.metadata.file_name.is_none() => name,
+            _ => {
+                return Ok(FieldData {
+                    metadata: layer.metadata.clone(),
+                    contents: layer.contents.clone(),
+                })
+            }
+        };
+        let name = String::from_utf8_lossy(reference).trim().to_string();
+        let templates = self.templates.read().unwrap();
+        let template = templates
+            .get(&name)
+            .ok_or(AppError::TemplateNotFound(name))?;
+        Ok(FieldData {
+            metadata: FieldMetadata {
+                name: layer.metadata.name.clone(),
+                content_type: Some(template.content_type.clone()),
+                ..Default::default()
+            },
+            contents: template.contents.clone(),
+        })
+    }
+
+    fn file_path(&self, name: &str, content_type: &str) -> Option<PathBuf> {
+        let extension = extension_from_mime_type(content_type)?;
+        Some(self.directory.join(format!("{}.{}", name, extension)))
+    }
+}
+
+pub async fn list_templates(State(state): State<AppState>) -> Json<Vec<TemplateInfo>> {
+    let templates = state.templates.templates.read().unwrap();
+    let mut infos: Vec<TemplateInfo> = templates
+        .iter()
+        .map(|(name, template)| TemplateInfo {
+            name: name.clone(),
+            content_type: template.content_type.clone(),
+        })
+        .collect();
+    infos.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(infos)
+}
+
+pub async fn upload_template(
+    State(state): State<AppState>,
+    UrlPath(name): UrlPath<String>,
+    headers: HeaderMap,
+    contents: Result<Bytes, BytesRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let contents = contents.map_err(|err| {
+        if err.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            AppError::PayloadTooLarge(state.config.max_upload_size)
+        } else {
+            AppError::Rejection(Box::new(err.into_response()))
+        }
+    })?;
+    if !is_valid_name(&name) {
+        return Err(AppError::InvalidTemplateName(name));
+    }
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(AppError::MissingMimeType)?
+        .to_string();
+    let store = &state.templates;
+    let path = store
+        .file_path(&name, &content_type)
+        .ok_or(AppError::InvalidMimeType(content_type.clone()))?;
+
+    // reject templates that would fail every request using them
+    let field = FieldData {
+        metadata: FieldMetadata {
+            content_type: Some(content_type.clone()),
+            ..Default::default()
+        },
+        contents: contents.clone(),
+    };
+    let is_image = ImageFormat::from_mime_type(&content_type).is_some();
+    let validation_state = state.clone();
+    state
+        .jobs
+        .run(move || {
+            if is_image {
+                decode_image(&field, validation_state.config.limits())?;
+            } else {
+                parse_svg(&field.contents, &validation_state.fonts.shared_options())?;
+            }
+            Ok(())
+        })
+        .await?;
+
+    tokio::fs::create_dir_all(&store.directory)
+        .await
+        .map_err(AppError::TemplateStorageFailure)?;
+    // a template may be replaced with one of a different type
+    if let Some(previous) = store.templates.read().unwrap().get(&name) {
+        if let Some(previous_path) = store.file_path(&name, &previous.content_type) {
+            if previous_path != path {
+                let _ = fs::remove_file(previous_path);
+            }
+        }
+    }
+    tokio::fs::write(&path, &contents)
+        .await
+        .map_err(AppError::TemplateStorageFailure)?;
+
+    store.templates.write().unwrap().insert(
+        name,
+        Template {
+            content_type,
+            contents,
+        },
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_template(
+    State(state): State<AppState>,
+    UrlPath(name): UrlPath<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = &state.templates;
+    let content_type = store
+        .templates
+        .read()
+        .unwrap()
+        .get(&name)
+        .map(|template| template.content_type.clone())
+        .ok_or(AppError::TemplateNotFound(name.clone()))?;
+    // the file goes first, a template that cannot be deleted stays usable and does not come
+    // back after a restart
+    if let Some(path) = store.file_path(&name, &content_type) {
+        match tokio::fs::remove_file(path).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                return Err(AppError::TemplateStorageFailure(err))
+            }
+            _ => {}
+        }
+    }
+    store.templates.write().unwrap().remove(&name);
+    Ok(StatusCode::NO_CONTENT)
+}
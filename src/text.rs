@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+
+use axum::body::Bytes;
+use axum_typed_multipart::FieldData;
+use imageproc::image::DynamicImage;
+use resvg::tiny_skia::{IntSize, NonZeroRect, Transform};
+use serde::Deserialize;
+
+use crate::{
+    fonts::SvgOptions,
+    layer::{LayerMetadata, PlacedLayer, Resampling},
+    limits::Limits,
+    svg::{escape, parse_svg, render_svg},
+    AppError,
+};
+
+const DEFAULT_FONT_SIZE: f32 = 32.0;
+const DEFAULT_LINE_HEIGHT: f32 = 1.2;
+const DEFAULT_COLOR: &str = "black";
+/// Longest content of a text layer in characters.
+const MAX_TEXT_LENGTH: usize = 10_000;
+
+/// A caption rendered with the fonts of the server, sent as a json `text` field.
+#[derive(Deserialize)]
+pub struct TextLayer {
+    /// The text, line breaks start a new line.
+    pub content: String,
+    /// Defaults to the default font family of the server.
+    pub font_family: Option<String>,
+    /// Font size in pixels.
+    pub size: Option<f32>,
+    /// Any svg color, defaults to black.
+    pub color: Option<String>,
+    pub stroke: Option<TextStroke>,
+    pub shadow: Option<TextShadow>,
+    #[serde(default)]
+    pub align: TextAlign,
+    /// Wraps the lines at word boundaries to stay within this width.
+    pub max_width: Option<u32>,
+    /// Distance between the baselines as a multiple of the font size.
+    pub line_height: Option<f32>,
+    /// Position, opacity, blend mode, filters, mask, tiling and z-index, the size and fit of a layer have no effect on text.
+    #[serde(flatten)]
+    pub placement: LayerMetadata,
+}
+
+/// Outline drawn behind the fill.
+#[derive(Deserialize)]
+pub struct TextStroke {
+    pub color: String,
+    pub width: f32,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct TextShadow {
+    /// Defaults to black.
+    pub color: Option<String>,
+    pub x: f32,
+    pub y: f32,
+    /// Standard deviation of the blur in pixels.
+    pub blur: f32,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum TextAlign {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+impl TextAlign {
+    fn text_anchor(self) -> &'static str {
+        match self {
+            TextAlign::Left => "start",
+            TextAlign::Center => "middle",
+            TextAlign::Right => "end",
+        }
+    }
+
+    /// Where the lines start within a box of `width`.
+    fn position(self, width: f32) -> f32 {
+        match self {
+            TextAlign::Left => 0.0,
+            TextAlign::Center => width / 2.0,
+            TextAlign::Right => width,
+        }
+    }
+}
+
+impl TextLayer {
+    /// Renders the text as svg and places it on a canvas of the given size.
+    pub fn render(
+        &self,
+        canvas_width: u32,
+        canvas_height: u32,
+        svg_options: &SvgOptions,
+        resampling: Resampling,
+        masks: &HashMap<String, FieldData<Bytes>>,
+        limits: Limits,
+    ) -> Result<PlacedLayer, AppError> {
+        if self.content.chars().count() > MAX_TEXT_LENGTH {
+            return Err(AppError::TextTooLong(MAX_TEXT_LENGTH));
+        }
+        let canvas = IntSize::from_wh(canvas_width, canvas_height).ok_or(AppError::InvalidSize)?;
+        let lines = match self.max_width {
+            Some(max_width) => self.wrap(max_width as f32, svg_options)?,
+            None => self.content.lines().map(String::from).collect(),
+        };
+        let mut text = self.text_element(&lines);
+        let mut tree = parse_svg(document(&text).as_bytes(), svg_options)?;
+
+        if let Some(shadow) = &self.shadow {
+            // the default filter region is relative to the text and cuts off far or blurry shadows
+            let bounds = tree.root().abs_stroke_bounding_box();
+            let margin = shadow.blur.max(0.0) * 3.0;
+            let region = NonZeroRect::from_ltrb(
+                bounds.left().min(bounds.left() + shadow.x - margin),
+                bounds.top().min(bounds.top() + shadow.y - margin),
+                bounds.right().max(bounds.right() + shadow.x + margin),
+                bounds.bottom().max(bounds.bottom() + shadow.y + margin),
+            );
+            if let Some(region) = region {
+                text = format!(
+                    r#"<filter id="shadow" filterUnits="userSpaceOnUse" x="{}" y="{}" width="{}" height="{}"><feDropShadow dx="{}" dy="{}" stdDeviation="{}" flood-color="{}"/></filter><g filter="url(#shadow)">{}</g>"#,
+                    region.x(),
+                    region.y(),
+                    region.width(),
+                    region.height(),
+                    shadow.x,
+                    shadow.y,
+                    shadow.blur.max(0.0),
+                    escape(shadow.color.as_deref().unwrap_or(DEFAULT_COLOR)),
+                    text
+                );
+                tree = parse_svg(document(&text).as_bytes(), svg_options)?;
+            }
+        }
+
+        let image = if tree.root().has_children() {
+            let bounds = tree.root().abs_layer_bounding_box();
+            // with a max width the lines are aligned within that box instead of the widest line
+            let (left, right) = match self.max_width {
+                Some(max_width) => (bounds.left().min(0.0), bounds.right().max(max_width as f32)),
+                None => (bounds.left(), bounds.right()),
+            };
+            let (left, top) = (left.floor(), bounds.top().floor());
+            let size = IntSize::from_wh(
+                (right.ceil() - left) as u32,
+                (bounds.bottom().ceil() - top) as u32,
+            )
+            .ok_or(AppError::InvalidSize)?;
+            limits.check_output(size.width(), size.height())?;
+            render_svg(&tree, size, Transform::from_translate(-left, -top))?
+        } else {
+            // nothing but whitespace
+            DynamicImage::new_rgba8(1, 1)
+        };
+        self.placement
+            .place(image, canvas, masks, svg_options, resampling, limits)
+    }
+
+    fn font_size(&self) -> f32 {
+        self.size.unwrap_or(DEFAULT_FONT_SIZE)
+    }
+
+    fn font_attributes(&self) -> String {
+        let mut attributes = format!(r#"font-size="{}""#, self.font_size());
+        if let Some(family) = &self.font_family {
+            attributes.push_str(&format!(r#" font-family="{}""#, escape(family)));
+        }
+        attributes
+    }
+
+    fn text_element(&self, lines: &[String]) -> String {
+        let size = self.font_size();
+        let line_height = self.line_height.unwrap_or(DEFAULT_LINE_HEIGHT) * size;
+        let x = self
+            .align
+            .position(self.max_width.unwrap_or_default() as f32);
+        let mut element = format!(
+            r#"<text {} fill="{}" text-anchor="{}""#,
+            self.font_attributes(),
+            escape(self.color.as_deref().unwrap_or(DEFAULT_COLOR)),
+            self.align.text_anchor()
+        );
+        if let Some(stroke) = &self.stroke {
+            element.push_str(&format!(
+                r#" stroke="{}" stroke-width="{}" stroke-linejoin="round" paint-order="stroke""#,
+                escape(&stroke.color),
+                stroke.width
+            ));
+        }
+        element.push('>');
+        for (index, line) in lines.iter().enumerate() {
+            element.push_str(&format!(
+                r#"<tspan x="{}" y="{}">{}</tspan>"#,
+                x,
+                size + index as f32 * line_height,
+                escape(line)
+            ));
+        }
+        element.push_str("</text>");
+        element
+    }
+
+    /// Width of a single line as laid out by usvg.
+    fn measure(&self, line: &str, svg_options: &SvgOptions) -> Result<f32, AppError> {
+        let text = format!("<text {}>{}</text>", self.font_attributes(), escape(line));
+        let tree = parse_svg(document(&text).as_bytes(), svg_options)?;
+        Ok(tree.root().abs_bounding_box().width())
+    }
+
+    /// Greedy word wrapping, a word that is wider than `max_width` gets a line of its own.
+    ///
+    /// Every distinct word is measured once and a line is as wide as its words and the spaces
+    /// between them, instead of laying out every candidate line again.
+    fn wrap(&self, max_width: f32, svg_options: &SvgOptions) -> Result<Vec<String>, AppError> {
+        // the ink of two glyphs with a space between them, minus their ink, is the width a space
+        // adds between two words
+        let space = self.measure("x x", svg_options)? - 2.0 * self.measure("x", svg_options)?;
+        let mut widths = HashMap::new();
+        let mut lines = vec![];
+        for paragraph in self.content.lines() {
+            let mut line = String::new();
+            let mut line_width = 0.0;
+            for word in paragraph.split_whitespace() {
+                let width = match widths.get(word) {
+                    Some(&width) => width,
+                    None => {
+                        let width = self.measure(word, svg_options)?;
+                        widths.insert(word, width);
+                        width
+                    }
+                };
+                if line.is_empty() {
+                    line.push_str(word);
+                    line_width = width;
+                } else if line_width + space + width > max_width {
+                    lines.push(std::mem::replace(&mut line, word.to_string()));
+                    line_width = width;
+                } else {
+                    line.push(' ');
+                    line.push_str(word);
+                    line_width += space + width;
+                }
+            }
+            lines.push(line);
+        }
+        Ok(lines)
+    }
+}
+
+fn document(content: &str) -> String {
+    format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="1" height="1">{}</svg>"#,
+        content
+    )
+}
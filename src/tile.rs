@@ -0,0 +1,80 @@
+use imageproc::image::{imageops::overlay, DynamicImage, RgbaImage};
+use resvg::tiny_skia::{
+    FilterQuality, IntSize, Paint, Pattern, Pixmap, Rect, SpreadMode, Transform,
+};
+use serde::Deserialize;
+
+use crate::{
+    layer::Anchor,
+    limits::Limits,
+    svg::{image_to_pixmap, pixmap_to_image},
+    AppError,
+};
+
+/// Repeats a layer in a grid over the whole image, e.g. for watermarks.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Tile {
+    /// Gap between the tiles in pixels.
+    pub spacing: u32,
+    /// Clockwise rotation of the grid in degrees.
+    pub angle: f32,
+}
+
+impl Tile {
+    /// Fills a canvas with copies of `image`, one of them is placed at the anchor and moved
+    /// by `offset` like a layer that is not tiled, the grid is rotated around its center.
+    pub fn fill(
+        &self,
+        image: &DynamicImage,
+        canvas: IntSize,
+        anchor: Anchor,
+        offset: (i64, i64),
+        limits: Limits,
+    ) -> Result<DynamicImage, AppError> {
+        if self.spacing > canvas.width() || self.spacing > canvas.height() {
+            return Err(AppError::InvalidTransform(
+                "The spacing of a tile cannot be larger than the image".into(),
+            ));
+        }
+        // every cell has half of the gap on each side, so the tile sits in its middle
+        let width = image
+            .width()
+            .checked_add(self.spacing)
+            .ok_or(AppError::InvalidSize)?;
+        let height = image
+            .height()
+            .checked_add(self.spacing)
+            .ok_or(AppError::InvalidSize)?;
+        limits.check_output(width, height)?;
+        let mut cell = RgbaImage::new(width, height);
+        let margin = (self.spacing / 2) as i64;
+        overlay(&mut cell, &image.to_rgba8(), margin, margin);
+        let cell = image_to_pixmap(&cell)?;
+
+        let (x, y) = anchor.align((cell.width(), cell.height()), canvas.dimensions());
+        let (half_width, half_height) = (cell.width() as f32 / 2.0, cell.height() as f32 / 2.0);
+        let transform = Transform::from_translate(
+            (x + offset.0) as f32 + half_width,
+            (y + offset.1) as f32 + half_height,
+        )
+        .pre_rotate(self.angle)
+        .pre_translate(-half_width, -half_height);
+        let quality = if self.angle % 360.0 == 0.0 {
+            FilterQuality::Nearest
+        } else {
+            FilterQuality::Bicubic
+        };
+
+        let mut pixmap =
+            Pixmap::new(canvas.width(), canvas.height()).ok_or(AppError::InvalidSize)?;
+        let paint = Paint {
+            shader: Pattern::new(cell.as_ref(), SpreadMode::Repeat, quality, 1.0, transform),
+            ..Paint::default()
+        };
+        let rect = Rect::from_xywh(0.0, 0.0, canvas.width() as f32, canvas.height() as f32)
+            .ok_or(AppError::InvalidSize)?;
+        pixmap.fill_rect(rect, &paint, Transform::identity(), None);
+        Ok(DynamicImage::ImageRgba8(pixmap_to_image(&pixmap)))
+    }
+}
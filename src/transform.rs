@@ -0,0 +1,179 @@
+use imageproc::{
+    geometric_transformations::{warp_into, Interpolation, Projection},
+    image::{DynamicImage, Rgba, RgbaImage},
+};
+use resvg::tiny_skia::IntSize;
+use serde::Deserialize;
+
+use crate::{
+    layer::{Anchor, Fit, Resampling},
+    limits::Limits,
+    AppError,
+};
+
+/// An operation on the base image, applied in order before the layers are composited.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum Operation {
+    /// Resizes to `width` x `height`, a missing side follows the aspect ratio.
+    Resize {
+        width: Option<u32>,
+        height: Option<u32>,
+        fit: Option<Fit>,
+        /// Which part is kept when `fit` is cover, defaults to center.
+        anchor: Option<Anchor>,
+        resampling: Option<Resampling>,
+    },
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// Rotates clockwise by `angle` degrees, other angles than multiples of 90 grow the image
+    /// to fit the rotated corners and leave them transparent.
+    Rotate {
+        angle: f32,
+    },
+    Flip {
+        direction: FlipDirection,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum FlipDirection {
+    Horizontal,
+    Vertical,
+}
+
+pub fn apply_transforms(
+    image: DynamicImage,
+    operations: &[Operation],
+    resampling: Resampling,
+    limits: Limits,
+) -> Result<DynamicImage, AppError> {
+    operations.iter().try_fold(image, |image, operation| {
+        operation.apply(image, resampling, limits)
+    })
+}
+
+impl Operation {
+    fn apply(
+        &self,
+        image: DynamicImage,
+        resampling: Resampling,
+        limits: Limits,
+    ) -> Result<DynamicImage, AppError> {
+        match *self {
+            Operation::Resize {
+                width,
+                height,
+                fit,
+                anchor,
+                resampling: operation_resampling,
+            } => {
+                let original =
+                    IntSize::from_wh(image.width(), image.height()).ok_or(AppError::InvalidSize)?;
+                let target = match (width, height) {
+                    (Some(width), Some(height)) => IntSize::from_wh(width, height),
+                    (Some(width), None) => original.scale_to_width(width),
+                    (None, Some(height)) => original.scale_to_height(height),
+                    (None, None) => Some(original),
+                }
+                .ok_or(AppError::InvalidTransform(
+                    "resize needs a width and height above 0".into(),
+                ))?;
+                let fit = fit.unwrap_or(Fit::Contain);
+                let size = fit.size(original, target);
+                limits.check_output(size.width(), size.height())?;
+                let filter = operation_resampling.unwrap_or(resampling).into();
+                let resized = if size == original {
+                    image
+                } else {
+                    image.resize_exact(size.width(), size.height(), filter)
+                };
+                if let Fit::Cover = fit {
+                    let (x, y) = anchor
+                        .unwrap_or(Anchor::Center)
+                        .align(target.dimensions(), (resized.width(), resized.height()));
+                    return Ok(resized.crop_imm(
+                        x.max(0) as u32,
+                        y.max(0) as u32,
+                        target.width(),
+                        target.height(),
+                    ));
+                }
+                Ok(resized)
+            }
+            Operation::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                if width == 0
+                    || height == 0
+                    || x.saturating_add(width) > image.width()
+                    || y.saturating_add(height) > image.height()
+                {
+                    return Err(AppError::InvalidTransform(format!(
+                        "crop {}x{} at {},{} is outside of the {}x{} image",
+                        width,
+                        height,
+                        x,
+                        y,
+                        image.width(),
+                        image.height()
+                    )));
+                }
+                Ok(image.crop_imm(x, y, width, height))
+            }
+            Operation::Rotate { angle } => {
+                let angle = angle.rem_euclid(360.0);
+                Ok(match angle {
+                    0.0 => image,
+                    90.0 => {
+                        limits.check_output(image.height(), image.width())?;
+                        image.rotate90()
+                    }
+                    180.0 => image.rotate180(),
+                    270.0 => {
+                        limits.check_output(image.height(), image.width())?;
+                        image.rotate270()
+                    }
+                    _ => {
+                        DynamicImage::ImageRgba8(rotate_expanded(&image.to_rgba8(), angle, limits)?)
+                    }
+                })
+            }
+            Operation::Flip { direction } => Ok(match direction {
+                FlipDirection::Horizontal => image.fliph(),
+                FlipDirection::Vertical => image.flipv(),
+            }),
+        }
+    }
+}
+
+/// Rotates about the center into a canvas large enough for the rotated image.
+fn rotate_expanded(image: &RgbaImage, degrees: f32, limits: Limits) -> Result<RgbaImage, AppError> {
+    let theta = degrees.to_radians();
+    let (width, height) = (image.width() as f32, image.height() as f32);
+    let (sin, cos) = (theta.sin().abs(), theta.cos().abs());
+    let rotated_width = (width * cos + height * sin).ceil();
+    let rotated_height = (width * sin + height * cos).ceil();
+    limits.check_output(rotated_width as u32, rotated_height as u32)?;
+
+    let projection = Projection::translate(rotated_width / 2.0, rotated_height / 2.0)
+        * Projection::rotate(theta)
+        * Projection::translate(-width / 2.0, -height / 2.0);
+    let mut rotated = RgbaImage::new(rotated_width as u32, rotated_height as u32);
+    warp_into(
+        image,
+        &projection,
+        Interpolation::Bilinear,
+        Rgba([0, 0, 0, 0]),
+        &mut rotated,
+    );
+    Ok(rotated)
+}